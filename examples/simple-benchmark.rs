@@ -1,8 +1,8 @@
-use std::thread::sleep;
+use std::thread::{self, sleep};
 use std::time::{Duration, Instant};
 use tempfile::tempdir;
 
-use bricksdb::kv::{KVOptions, KV};
+use bricksdb::kv::{Durability, KVOptions, KV};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use sha2::{Sha256, Digest};
@@ -19,12 +19,24 @@ struct Args {
     /// Value 长度
     #[arg(short = 'v', long = "value", default_value_t = 4096)]
     value_len: usize,
+
+    /// 并发写入线程数
+    #[arg(short = 't', long = "threads", default_value_t = 1)]
+    threads: usize,
+
+    /// 每次写入 WAL 后 fsync
+    #[arg(long = "fsync", default_value_t = false)]
+    fsync: bool,
+
+    /// key 已是 SHA256 摘要，直接用 key 的比特定位 bucket 和 slot
+    #[arg(long = "pre-hashed", default_value_t = false)]
+    pre_hashed: bool,
 }
 
 /// 生成固定长度的 key（32 字节，SHA256(i)）
 fn make_key(i: u64) -> Vec<u8> {
     let mut hasher = Sha256::new();
-    hasher.update(&i.to_le_bytes());
+    hasher.update(i.to_le_bytes());
     hasher.finalize().to_vec()
 }
 
@@ -32,7 +44,7 @@ fn make_key(i: u64) -> Vec<u8> {
 fn make_value(i: u64, value_len: usize) -> Vec<u8> {
     let mut hasher = Sha256::new();
     hasher.update(b"VAL");
-    hasher.update(&i.to_le_bytes());
+    hasher.update(i.to_le_bytes());
     let base = hasher.finalize();
 
     if value_len <= base.len() {
@@ -61,17 +73,24 @@ fn report(name: &str, n: u64, elapsed_ms: f64) {
     );
 }
 
-/// 随机写入测试
-fn bench_put_random(kv: &KV, n: u64, value_len: usize) {
+/// 随机写入测试，写入平均分配给 threads 个线程
+fn bench_put_random(kv: &KV, n: u64, value_len: usize, threads: usize) {
     let mut indices: Vec<u64> = (0..n).collect();
     indices.shuffle(&mut thread_rng());
+    let chunk_size = indices.len().div_ceil(threads.max(1)).max(1);
 
     let start = Instant::now();
-    for i in indices {
-        let key = make_key(i);
-        let value = make_value(i, value_len);
-        kv.put(key, value).unwrap();
-    }
+    thread::scope(|scope| {
+        for chunk in indices.chunks(chunk_size) {
+            scope.spawn(move || {
+                for &i in chunk {
+                    let key = make_key(i);
+                    let value = make_value(i, value_len);
+                    kv.put(key, value).unwrap();
+                }
+            });
+        }
+    });
     let elapsed_ms = start.elapsed().as_millis();
     report("write random", n, elapsed_ms as f64);
 }
@@ -95,18 +114,26 @@ fn bench_read_random(kv: &KV, n: u64, value_len: usize) {
 fn main() {
     // 解析命令行参数
     let args = Args::parse();
-    println!("Using n={} value_len={}", args.n, args.value_len);
+    println!(
+        "Using n={} value_len={} threads={} fsync={} pre_hashed={}",
+        args.n, args.value_len, args.threads, args.fsync, args.pre_hashed
+    );
 
     let dir = tempdir().unwrap();
     println!("Benchmarking in {}", dir.path().display());
 
     let mut kv_options = KVOptions::default();
-    kv_options.wal_options.fsync = false;
+    kv_options.wal_options.durability = if args.fsync {
+        Durability::Sync
+    } else {
+        Durability::None
+    };
+    kv_options.key_store_options.pre_hashed = args.pre_hashed;
 
     let kv = KV::new(dir.path(), kv_options).unwrap();
 
     sleep(Duration::from_secs(3));
 
-    bench_put_random(&kv, args.n, args.value_len);
+    bench_put_random(&kv, args.n, args.value_len, args.threads);
     bench_read_random(&kv, args.n, args.value_len);
 }
\ No newline at end of file
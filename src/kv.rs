@@ -1,61 +1,144 @@
+mod cache;
+mod commit;
 mod data;
+mod flush;
 mod index;
+mod iter;
+mod key_layout;
+mod lock;
 mod meta;
+mod periodic_sync;
 mod utils;
 mod wal;
+mod write_stall;
 
+use crate::kv::cache::{CacheOptions, KVCache};
+use crate::kv::commit::GroupCommit;
 use crate::kv::data::level_page_bitmap::LevelPageOptions;
+use crate::kv::flush::{FlushContext, FlushState};
 use crate::kv::index::buckets::BucketsOptions;
+use crate::kv::key_layout::KeyLayout;
+use crate::kv::lock::DirLock;
 use crate::kv::meta::Meta;
+use crate::kv::periodic_sync::PeriodicSync;
 use crate::kv::utils::{path_exist, remove_file_if_exists};
-use crate::kv::wal::{WAL, get_all_wal_ids, wal_file_path};
+use crate::kv::wal::{
+    ReplayResult, TAGGED_BATCH_WAL_VERSION, WAL, WAL_VERSION, WALCompressor, get_all_wal_ids,
+    wal_file_path,
+};
+use crate::kv::write_stall::{Pending, WriteStall};
 use data::level_page_bitmap;
 use index::bucket::BucketValue;
 use index::buckets::{Buckets, BucketsError};
-use log::error;
+use log::{error, warn};
 use std::collections::HashMap;
-use std::fs::{create_dir, create_dir_all};
-use std::hash::Hash;
-use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-use std::{error, fs, io, thread};
-use quick_cache::sync::Cache;
-
-struct FlushingBuffer {
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::{error, io, thread};
+
+pub use flush::FlushHandle;
+pub use iter::{Iter, Keys};
+pub use key_layout::{MAX_KEY_SIZE, MIN_VARIABLE_KEY_SIZE};
+pub use wal::{WALRecord, WALRecords};
+pub use write_stall::{StopBehavior, WriteStallCondition, WriteStallOptions, WriteStallStats};
+
+pub(crate) struct FlushingBuffer {
     buffer: HashMap<Vec<u8>, KVOp>,
+    // Estimated memory of the buffer, see `entry_size`
+    size: usize,
+    wal_id: u64,
     wal_path: PathBuf,
 }
 
 pub struct KV {
     dir: PathBuf,
-    meta: RwLock<Meta>,
+    meta: Arc<RwLock<Meta>>,
     level_page_bitmap: Arc<level_page_bitmap::LevelPage>,
     buckets_index: Arc<Buckets<DataInfo>>,
-    key_size: u32,
-    current_wal: RwLock<WAL>,
+    cache: Arc<KVCache>,
+    key_layout: KeyLayout,
+    current_wal: Arc<RwLock<WAL>>,
+    wal_compressor: WALCompressor,
     current_wal_id: AtomicU64,
+    // Sequence number of the last batch written to the WAL
+    last_seq: AtomicU64,
     current_buffer: RwLock<HashMap<Vec<u8>, KVOp>>,
-    flushing_buffers: Arc<RwLock<Vec<FlushingBuffer>>>,
-    flush_lock: Arc<Mutex<()>>,
+    // Estimated memory of current_buffer, only changed under its write lock
+    current_buffer_size: AtomicUsize,
+    // Shared with the flush thread, which does not hold the lock while flushing one
+    flushing_buffers: Arc<RwLock<Vec<Arc<FlushingBuffer>>>>,
+    flush_state: Arc<FlushState>,
+    group_commit: GroupCommit,
+    write_stall: WriteStall,
+    periodic_sync: Option<PeriodicSync>,
     wal_flush_size: u32,
     opts: KVOptions,
+    read_only: bool,
+    closed: bool,
+    // Declared last so it is released after every other file is closed
+    _lock: DirLock,
 }
 
 #[derive(Clone, Debug)]
-struct DataInfo {
-    data_id: u64,
-    data_len: u32,
+pub(crate) enum DataInfo {
+    /// The value is stored in the value store, chained when larger than its largest page
+    Paged { data_id: u64, data_len: u32 },
+    /// The value is stored in the bucket entry, see `BucketsOptions::inline_value_size`
+    Inline(Vec<u8>),
+}
+
+// Data id marking an inline value, never handed out by the value store
+const INLINE_DATA_ID: u64 = u64::MAX;
+
+impl DataInfo {
+    /// Read the value, without any I/O when it is inline
+    fn read(&self, level_page: &level_page_bitmap::LevelPage) -> io::Result<Vec<u8>> {
+        match self {
+            DataInfo::Paged { data_id, data_len } => level_page.read_value(*data_id, *data_len),
+            DataInfo::Inline(value) => Ok(value.clone()),
+        }
+    }
+
+    /// Pages of the value store holding the value
+    fn pages(&self, level_page: &level_page_bitmap::LevelPage) -> io::Result<Vec<u64>> {
+        match self {
+            DataInfo::Paged { data_id, data_len } => level_page.value_pages(*data_id, *data_len),
+            DataInfo::Inline(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Release the pages holding the value
+    fn free(&self, level_page: &level_page_bitmap::LevelPage) -> io::Result<()> {
+        match self {
+            DataInfo::Paged { data_id, data_len } => level_page.free_value(*data_id, *data_len),
+            DataInfo::Inline(_) => Ok(()),
+        }
+    }
 }
 
 impl BucketValue for DataInfo {
+    // Size of the paged layout, which stores created before inlining were sized for
+    const ENCODED_SIZE: u32 = 16;
+
     fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(8 + 4);
-        buf.extend(&self.data_id.to_le_bytes());
-        buf.extend(&self.data_len.to_le_bytes());
-        buf
+        match self {
+            DataInfo::Paged { data_id, data_len } => {
+                let mut buf = Vec::with_capacity(8 + 4);
+                buf.extend(&data_id.to_le_bytes());
+                buf.extend(&data_len.to_le_bytes());
+                buf
+            }
+            DataInfo::Inline(value) => {
+                let mut buf = Vec::with_capacity(8 + 4 + value.len());
+                buf.extend(&INLINE_DATA_ID.to_le_bytes());
+                buf.extend(&(value.len() as u32).to_le_bytes());
+                buf.extend(value);
+                buf
+            }
+        }
     }
 
     fn decode(bytes: &[u8]) -> Option<Self> {
@@ -64,15 +147,49 @@ impl BucketValue for DataInfo {
         }
         let data_id = u64::from_le_bytes(bytes[0..8].try_into().ok()?);
         let data_len = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
-        Some(DataInfo { data_id, data_len })
+        if data_id == INLINE_DATA_ID {
+            let value = bytes.get(12..12 + data_len as usize)?;
+            return Some(DataInfo::Inline(value.to_vec()));
+        }
+        Some(DataInfo::Paged { data_id, data_len })
     }
 }
 
+/// Estimated memory of the buffers, keys and values plus a fixed overhead per entry
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct BufferUsage {
+    /// Buffer of the current WAL
+    pub current_bytes: usize,
+    /// Buffers waiting to be flushed
+    pub flushing_bytes: usize,
+    pub flushing_buffers: usize,
+}
+
 /// General KV error
 #[derive(Debug)]
 pub enum KVError {
     Io(io::Error),
     InvalidKeyLength,
+    /// The options requested on open differ from the ones the store was created with
+    OptionsMismatch {
+        option: &'static str,
+        stored: String,
+        requested: String,
+    },
+    /// The directory is already opened by another KV, in this or another process
+    Locked,
+    /// The KV was opened with `KV::open_read_only`
+    ReadOnly,
+    /// A WAL record failed its checksum or could not be decoded
+    CorruptedWAL { wal_id: u64, offset: u64 },
+    /// Too much data waits to be flushed, see `WriteStallOptions`
+    WriteStall,
+    /// A value is larger than `MAX_VALUE_SIZE`
+    ValueTooLarge,
+    /// The encoded batch is larger than `MAX_BATCH_SIZE`
+    BatchTooLarge,
+    /// The options cannot be used together, e.g. a `key_size` too small for variable-length keys
+    InvalidOptions(String),
     Other(String),
 }
 
@@ -83,6 +200,15 @@ impl From<BucketsError> for KVError {
             BucketsError::Io(e) => KVError::Io(e),
             BucketsError::InvalidKeyLength => KVError::InvalidKeyLength,
             BucketsError::MaxSearchReached => KVError::Other("Max search reached".to_string()),
+            BucketsError::OptionsMismatch {
+                option,
+                stored,
+                requested,
+            } => KVError::OptionsMismatch {
+                option,
+                stored,
+                requested,
+            },
             BucketsError::Other(s) => KVError::Other(s),
         }
     }
@@ -102,6 +228,24 @@ impl std::fmt::Display for KVError {
             KVError::Io(e) => write!(f, "IO error: {}", e),
             KVError::Other(s) => write!(f, "Other error: {}", s),
             &KVError::InvalidKeyLength => write!(f, "InvalidKeyLength error"),
+            KVError::Locked => write!(f, "Directory is locked by another KV"),
+            KVError::ReadOnly => write!(f, "KV is opened read-only"),
+            KVError::WriteStall => write!(f, "Writes are stalled until buffers are flushed"),
+            KVError::ValueTooLarge => write!(f, "Value larger than {} bytes", MAX_VALUE_SIZE),
+            KVError::BatchTooLarge => write!(f, "Batch larger than {} bytes", MAX_BATCH_SIZE),
+            KVError::InvalidOptions(s) => write!(f, "Invalid options: {}", s),
+            KVError::CorruptedWAL { wal_id, offset } => {
+                write!(f, "WAL {} is corrupt at offset {}", wal_id, offset)
+            }
+            KVError::OptionsMismatch {
+                option,
+                stored,
+                requested,
+            } => write!(
+                f,
+                "Option {} mismatch: stored {}, requested {}",
+                option, stored, requested
+            ),
         }
     }
 }
@@ -115,17 +259,114 @@ const WAL_DIR_NAME: &str = "wal";
 
 const KV_META_FILE_NAME: &str = "kv.meta";
 
+/// Largest value accepted by `KV::batch`, values above the largest page size are stored
+/// in chained pages. Lengths are u32 in WAL records and in the key store.
+pub const MAX_VALUE_SIZE: usize = 1 << 30;
+
+/// Largest encoded batch accepted by `KV::batch`. A batch is a single WAL record whose
+/// length is a u32, this leaves room for the compression overhead.
+pub const MAX_BATCH_SIZE: usize = 1 << 31;
+
+/// What to do with a corrupt WAL record when replaying on open.
+///
+/// An incomplete record at the end of a WAL, with no checksum mismatch, is a torn write
+/// that was never acknowledged: it is a normal result of a crash and dropped under every
+/// policy. The policy only applies to records with a bad checksum or header.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum WALRecoveryPolicy {
+    /// Drop the corrupt record and every record after it
+    #[default]
+    TruncateTail,
+    /// Skip corrupt records and keep replaying the rest
+    SkipCorrupt,
+    /// Fail to open with `KVError::CorruptedWAL`, the WAL is left untouched
+    Fail,
+}
+
+/// When a WAL append is synced to disk before the write returns
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Durability {
+    /// Never sync, data is lost if the OS crashes before writing back the page cache
+    None,
+    /// Sync from a background thread every given number of milliseconds, which must not be 0
+    Periodic(u64),
+    /// `fdatasync` after every batch, file metadata such as mtime may lag behind
+    DataSync,
+    /// `fsync` after every batch
+    #[default]
+    Sync,
+}
+
+impl Durability {
+    fn strength(self) -> u8 {
+        match self {
+            Durability::None => 0,
+            Durability::Periodic(_) => 1,
+            Durability::DataSync => 2,
+            Durability::Sync => 3,
+        }
+    }
+}
+
+/// How WAL records are compressed, stored per record so WAL files written
+/// with different settings replay correctly
+#[derive(Clone, PartialEq, Debug)]
+pub enum WALCompression {
+    None,
+    Zstd { level: i32 },
+    /// zstd with a dictionary, e.g. trained by `zstd::dict::from_samples` on encoded batches.
+    /// It is saved in the WAL directory for replay.
+    ZstdDict { level: i32, dictionary: Arc<Vec<u8>> },
+}
+
+impl Default for WALCompression {
+    fn default() -> Self {
+        WALCompression::Zstd { level: 3 }
+    }
+}
+
+/// Progress of replaying the WAL files left by the previous run, reported on open
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RecoveryProgress {
+    /// WAL that was just replayed
+    pub wal_id: u64,
+    /// Number of WAL files replayed so far, this one included
+    pub replayed: usize,
+    /// Number of WAL files to replay
+    pub total: usize,
+}
+
+/// Called once for every replayed WAL file, from the recovery threads in completion order
+pub type RecoveryCallback = Arc<dyn Fn(RecoveryProgress) + Send + Sync>;
+
 #[derive(Clone)]
 pub struct WALOptions {
+    /// Rotate the WAL and its buffer once the WAL file is larger than this many bytes
     pub flush_size: u32,
-    pub fsync: bool,
+    /// Rotate as well once the buffer takes more than this many bytes of memory,
+    /// compressed WAL records can be much smaller than their buffered values
+    pub max_buffer_memory: usize,
+    pub durability: Durability,
+    pub recovery_policy: WALRecoveryPolicy,
+    /// Number of threads decoding WAL files before the current one on open
+    pub recovery_threads: usize,
+    pub recovery_progress: Option<RecoveryCallback>,
+    pub compression: WALCompression,
+    /// Records shorter than this many bytes are stored uncompressed
+    pub compression_threshold: usize,
 }
 
 impl Default for WALOptions {
     fn default() -> Self {
         WALOptions {
             flush_size: 4 * 1024 * 1024,
-            fsync: true,
+            max_buffer_memory: 64 * 1024 * 1024,
+            durability: Durability::default(),
+            recovery_policy: WALRecoveryPolicy::default(),
+            recovery_threads: thread::available_parallelism().map_or(1, |n| n.get().min(8)),
+            recovery_progress: None,
+            compression: WALCompression::default(),
+            compression_threshold: 256,
         }
     }
 }
@@ -135,16 +376,233 @@ pub struct KVOptions {
     pub key_store_options: BucketsOptions,
     pub value_store_options: LevelPageOptions,
     pub wal_options: WALOptions,
+    pub cache_options: CacheOptions,
+    pub write_stall_options: WriteStallOptions,
 }
 
+// Operation tags of batch entries in the WAL, new operations get new tags
+const OP_PUT: u8 = 1;
+const OP_DEL: u8 = 2;
+
 /// Represents a single KV operation: Put or Delete
 pub enum KVOp {
     Put { value: Vec<u8> },
     Del {},
 }
 
+/// A group of puts and deletes applied atomically.
+///
+/// The whole batch is encoded as a single WAL record, so after a crash
+/// `KV::load` replays either every operation of the batch or none of them.
+/// Operations on the same key are applied in insertion order.
+#[derive(Default)]
 pub struct Batch {
-    ops: Vec<(Vec<u8>, KVOp)>,
+    ops: BatchOps,
+    durability: Option<Durability>,
+}
+
+type BatchOps = Vec<(Vec<u8>, KVOp)>;
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push((key, KVOp::Put { value }));
+        self
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push((key, KVOp::Del {}));
+        self
+    }
+
+    /// Override `WALOptions::durability` for this batch.
+    ///
+    /// Batches committed together are synced once with the strongest durability among them.
+    /// `Periodic` is only accepted if the KV was opened with `Periodic`, whose sync thread
+    /// and interval apply; otherwise the batch fails with `KVError::InvalidOptions`.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = Some(durability);
+        self
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Remove all operations, keeping the allocation for reuse
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// Length of the record written by `encode`
+    fn encoded_len(&self) -> usize {
+        let size: usize = self
+            .ops
+            .iter()
+            .map(|(key, op)| match op {
+                KVOp::Put { value } => 9 + key.len() + value.len(),
+                KVOp::Del {} => 9 + key.len(),
+            })
+            .sum();
+        12 + size
+    }
+
+    /// Encode as a single WAL record: `[seq u64][count u32]`, then for every operation
+    /// `[op u8][key_len u32][key][value_len u32][value]`, a delete has an empty value
+    fn encode(&self, seq: u64) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.encoded_len());
+        payload.extend_from_slice(&seq.to_le_bytes());
+        payload.extend_from_slice(&(self.ops.len() as u32).to_le_bytes());
+        for (key, op) in &self.ops {
+            let (tag, value): (u8, &[u8]) = match op {
+                KVOp::Put { value } => (OP_PUT, value),
+                KVOp::Del {} => (OP_DEL, &[]),
+            };
+            payload.push(tag);
+            payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            payload.extend_from_slice(key);
+            payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            payload.extend_from_slice(value);
+        }
+        payload
+    }
+}
+
+/// Decode a batch record written before entries were tagged, None if the payload is malformed.
+///
+/// Entries are `[entry_len u32][key][value]` split by key_size, so an empty value reads as a delete.
+fn decode_legacy_batch(batch_payload: &[u8], key_size: usize) -> Option<BatchOps> {
+    let mut ops = Vec::new();
+    // Skip the 4-byte total size prefix
+    let mut batch_offset = 4;
+    while batch_offset < batch_payload.len() {
+        let entry_len = read_u32(batch_payload, &mut batch_offset)? as usize;
+        let entry_payload = read_bytes(batch_payload, &mut batch_offset, entry_len)?;
+
+        let key = entry_payload.get(..key_size)?.to_vec();
+        if entry_len > key_size {
+            // Put operation
+            let value = entry_payload[key_size..].to_vec();
+            ops.push((key, KVOp::Put { value }));
+        } else {
+            // Delete operation
+            ops.push((key, KVOp::Del {}));
+        }
+    }
+    Some(ops)
+}
+
+/// Decode a batch record encoded by `Batch::encode` into its sequence number and operations,
+/// None if the payload is malformed or holds an unknown operation
+fn decode_batch(batch_payload: &[u8]) -> Option<(u64, BatchOps)> {
+    let mut offset = 0;
+    let seq = u64::from_le_bytes(read_bytes(batch_payload, &mut offset, 8)?.try_into().ok()?);
+    let count = read_u32(batch_payload, &mut offset)? as usize;
+    // Not preallocated from `count`, a corrupt count must not cause a huge allocation
+    let mut ops = Vec::new();
+    for _ in 0..count {
+        let tag = *read_bytes(batch_payload, &mut offset, 1)?.first()?;
+        let key_len = read_u32(batch_payload, &mut offset)? as usize;
+        let key = read_bytes(batch_payload, &mut offset, key_len)?.to_vec();
+        let value_len = read_u32(batch_payload, &mut offset)? as usize;
+        let value = read_bytes(batch_payload, &mut offset, value_len)?.to_vec();
+        let op = match tag {
+            OP_PUT => KVOp::Put { value },
+            OP_DEL => KVOp::Del {},
+            _ => return None,
+        };
+        ops.push((key, op));
+    }
+    (offset == batch_payload.len()).then_some((seq, ops))
+}
+
+fn read_bytes<'a>(buf: &'a [u8], offset: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = buf.get(*offset..offset.checked_add(len)?)?;
+    *offset += len;
+    Some(bytes)
+}
+
+fn read_u32(buf: &[u8], offset: &mut usize) -> Option<u32> {
+    Some(u32::from_le_bytes(read_bytes(buf, offset, 4)?.try_into().ok()?))
+}
+
+// Memory of a buffer entry besides the key and value bytes, hash table slack is not counted
+const ENTRY_OVERHEAD: usize = size_of::<(Vec<u8>, KVOp)>();
+
+/// Estimated memory an entry takes in a buffer
+fn entry_size(key: &[u8], op: &KVOp) -> usize {
+    match op {
+        KVOp::Put { value } => ENTRY_OVERHEAD + key.len() + value.len(),
+        KVOp::Del {} => ENTRY_OVERHEAD + key.len(),
+    }
+}
+
+fn buffer_size(buffer: &HashMap<Vec<u8>, KVOp>) -> usize {
+    buffer.iter().map(|(key, op)| entry_size(key, op)).sum()
+}
+
+/// Replay a WAL into `buffer`, a corrupt batch is never partially applied.
+/// `last_seq` is raised to the highest sequence number replayed.
+fn replay_wal(
+    wal: &WAL,
+    policy: WALRecoveryPolicy,
+    key_size: usize,
+    buffer: &mut HashMap<Vec<u8>, KVOp>,
+    last_seq: &mut u64,
+) -> io::Result<ReplayResult> {
+    let skip_corrupt = policy == WALRecoveryPolicy::SkipCorrupt;
+    let legacy = wal.version() < TAGGED_BATCH_WAL_VERSION;
+    wal.replay(skip_corrupt, |batch_payload| {
+        let ops = if legacy {
+            decode_legacy_batch(&batch_payload, key_size)
+        } else {
+            decode_batch(&batch_payload).map(|(seq, ops)| {
+                *last_seq = (*last_seq).max(seq);
+                ops
+            })
+        };
+        match ops {
+            Some(ops) => {
+                buffer.extend(ops);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// A WAL replayed on open, not merged into the buffers yet
+struct ReplayedWAL {
+    buffer: HashMap<Vec<u8>, KVOp>,
+    result: ReplayResult,
+    last_seq: u64,
+}
+
+impl ReplayedWAL {
+    fn replay(wal: &WAL, opts: &KVOptions, key_size: u32) -> io::Result<Self> {
+        let mut buffer = HashMap::new();
+        let mut last_seq = 0;
+        let result = replay_wal(
+            wal,
+            opts.wal_options.recovery_policy,
+            key_size as usize,
+            &mut buffer,
+            &mut last_seq,
+        )?;
+        Ok(Self {
+            buffer,
+            result,
+            last_seq,
+        })
+    }
 }
 
 impl KV {
@@ -152,6 +610,44 @@ impl KV {
     pub fn new<P: Into<PathBuf>>(dir: P, opts: KVOptions) -> Result<Self, KVError> {
         let dir = dir.into();
         create_dir_all(&dir)?;
+        let lock = DirLock::acquire(&dir, true)?;
+        let key_layout = KeyLayout::new(
+            opts.key_store_options.key_size,
+            opts.key_store_options.max_key_size,
+        )?;
+        if opts.key_store_options.pre_hashed && !matches!(key_layout, KeyLayout::Fixed { .. }) {
+            // Encoded variable-length keys start with their length, not with hash bits
+            return Err(KVError::InvalidOptions(
+                "pre_hashed requires fixed-size keys".to_string(),
+            ));
+        }
+        if opts.key_store_options.pre_hashed && opts.key_store_options.key_size < 8 {
+            // Shorter keys leave the high bits that pick the bucket zero
+            return Err(KVError::InvalidOptions(
+                "pre_hashed requires key_size of at least 8".to_string(),
+            ));
+        }
+        if opts.wal_options.durability == Durability::Periodic(0) {
+            return Err(KVError::InvalidOptions(
+                "Durability::Periodic requires a non-zero interval".to_string(),
+            ));
+        }
+
+        // Check the stored layout first, before any store is opened with the wrong options
+        let kv_meta_file_path = dir.join(KV_META_FILE_NAME);
+        let stored_meta = if path_exist(&kv_meta_file_path)? {
+            let meta = Meta::load_from_file(&kv_meta_file_path)?;
+            if meta.key_size != opts.key_store_options.key_size {
+                return Err(KVError::OptionsMismatch {
+                    option: "key_size",
+                    stored: meta.key_size.to_string(),
+                    requested: opts.key_store_options.key_size.to_string(),
+                });
+            }
+            Some(meta)
+        } else {
+            None
+        };
 
         let level_page_bitmap = Arc::new(level_page_bitmap::LevelPage::new(
             dir.join(VALUE_STORE_DIR_NAME), // Each page_size file under the directory
@@ -160,18 +656,19 @@ impl KV {
 
         let bucket_index = Arc::new(Buckets::new(
             dir.join(KEY_STORE_DIR_NAME),
-            BucketsOptions::default(),
+            opts.key_store_options.clone(),
         )?);
 
-        let kv_meta_file_path = dir.join(KV_META_FILE_NAME);
         let mut kv_meta = Meta {
             current_wal_id: 0,
+            flushing_wal_id: None,
             key_size: opts.key_store_options.key_size,
+            last_seq: 0,
         };
         let mut need_load_data = false;
         let mut current_wal_path = wal_file_path(dir.to_path_buf().join(WAL_DIR_NAME).as_path(), 0);
-        if path_exist(&kv_meta_file_path)? {
-            kv_meta = Meta::load_from_file(&kv_meta_file_path)?;
+        if let Some(stored_meta) = stored_meta {
+            kv_meta = stored_meta;
             current_wal_path = wal_file_path(
                 dir.to_path_buf().join(WAL_DIR_NAME).as_path(),
                 kv_meta.current_wal_id,
@@ -181,307 +678,484 @@ impl KV {
             create_dir_all(dir.to_path_buf().join(WAL_DIR_NAME))?;
             kv_meta.save_to_file(&kv_meta_file_path)?;
         }
-        let current_wal = RwLock::new(WAL::open(
-            current_wal_path.as_path(),
-            opts.wal_options.fsync,
-        )?);
+        let current_wal = Arc::new(RwLock::new(WAL::open(current_wal_path.as_path())?));
+        let wal_compressor = WALCompressor::new(
+            &dir.join(WAL_DIR_NAME),
+            &opts.wal_options.compression,
+            opts.wal_options.compression_threshold,
+        )?;
         let current_wal_id = AtomicU64::new(kv_meta.current_wal_id);
-        let kv = Self {
+        let last_seq = AtomicU64::new(kv_meta.last_seq);
+        let mut kv = Self {
             dir: dir.to_path_buf(),
-            meta: RwLock::from(kv_meta),
+            meta: Arc::new(RwLock::new(kv_meta)),
             level_page_bitmap,
             buckets_index: bucket_index,
-            key_size: opts.key_store_options.key_size,
+            cache: Arc::new(KVCache::new(&opts.cache_options)),
+            key_layout,
             current_wal,
+            wal_compressor,
             current_wal_id,
+            last_seq,
             current_buffer: Default::default(),
+            current_buffer_size: AtomicUsize::new(0),
             flushing_buffers: Arc::new(Default::default()),
-            flush_lock: Arc::new(Mutex::new(())),
+            flush_state: Arc::new(FlushState::default()),
+            group_commit: GroupCommit::default(),
+            write_stall: WriteStall::new(opts.write_stall_options.clone()),
+            periodic_sync: None,
             wal_flush_size: opts.wal_options.flush_size,
             opts,
+            read_only: false,
+            closed: false,
+            _lock: lock,
         };
         if need_load_data {
             kv.load()?;
         }
+        {
+            // Batches are written in the version the WAL was created with,
+            // so move a WAL of an older version out of the way
+            let mut wal_with_write_lock = kv.current_wal.write().unwrap();
+            if wal_with_write_lock.version() < WAL_VERSION {
+                let mut buffer_with_write_lock = kv.current_buffer.write().unwrap();
+                kv.rotate_buffer(&mut wal_with_write_lock, &mut buffer_with_write_lock)?;
+            }
+        }
+        if let Durability::Periodic(interval_ms) = kv.opts.wal_options.durability {
+            kv.periodic_sync = Some(PeriodicSync::start(
+                kv.current_wal.clone(),
+                Duration::from_millis(interval_ms),
+            ));
+        }
+        // Flush WAL files left over by the previous run
+        if !kv.flushing_buffers.read().unwrap().is_empty() {
+            kv.trigger_async_flush();
+        }
+        Ok(kv)
+    }
+
+    /// Open an existing KV without writing anything to its directory, e.g. a copy of a live one.
+    ///
+    /// The layout is taken from the stored metadata. WAL files are replayed into memory but
+    /// never truncated or removed, no flush thread is started, and writes fail with
+    /// `KVError::ReadOnly`.
+    pub fn open_read_only<P: Into<PathBuf>>(dir: P) -> Result<Self, KVError> {
+        let dir = dir.into();
+        let lock = DirLock::acquire(&dir, false)?;
+
+        let kv_meta = Meta::load_from_file(dir.join(KV_META_FILE_NAME))?;
+        let level_page_bitmap = Arc::new(level_page_bitmap::LevelPage::open_read_only(
+            dir.join(VALUE_STORE_DIR_NAME),
+        )?);
+        let bucket_index = Arc::new(Buckets::open_read_only(dir.join(KEY_STORE_DIR_NAME))?);
+        let current_wal = Arc::new(RwLock::new(WAL::open_read_only(&wal_file_path(
+            &dir.join(WAL_DIR_NAME),
+            kv_meta.current_wal_id,
+        ))?));
+
+        let opts = KVOptions {
+            key_store_options: BucketsOptions {
+                key_size: kv_meta.key_size,
+                bucket_count: bucket_index.bucket_count(),
+                inline_value_size: bucket_index.inline_value_size(),
+                max_key_size: bucket_index.max_key_size(),
+                pre_hashed: bucket_index.pre_hashed(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // Writes are rejected, and saving a dictionary would write to the directory
+        let wal_compressor = WALCompressor::new(&dir.join(WAL_DIR_NAME), &WALCompression::None, 0)?;
+        let kv = Self {
+            dir,
+            current_wal_id: AtomicU64::new(kv_meta.current_wal_id),
+            last_seq: AtomicU64::new(kv_meta.last_seq),
+            key_layout: KeyLayout::new(kv_meta.key_size, bucket_index.max_key_size())?,
+            meta: Arc::new(RwLock::new(kv_meta)),
+            level_page_bitmap,
+            buckets_index: bucket_index,
+            cache: Arc::new(KVCache::new(&opts.cache_options)),
+            current_wal,
+            wal_compressor,
+            current_buffer: Default::default(),
+            current_buffer_size: AtomicUsize::new(0),
+            flushing_buffers: Arc::new(Default::default()),
+            flush_state: Arc::new(FlushState::default()),
+            group_commit: GroupCommit::default(),
+            write_stall: WriteStall::new(opts.write_stall_options.clone()),
+            periodic_sync: None,
+            wal_flush_size: opts.wal_options.flush_size,
+            opts,
+            read_only: true,
+            closed: false,
+            _lock: lock,
+        };
+        kv.load()?;
         Ok(kv)
     }
 
     pub fn load(&self) -> Result<(), KVError> {
+        let interrupted_flush = self.meta.read().unwrap().flushing_wal_id;
+        // Unreferenced pages are never read, a read-only KV leaves them to the next writer
+        if let Some(wal_id) = interrupted_flush.filter(|_| !self.read_only) {
+            // Must run before anything is flushed again, pages allocated from now on are referenced
+            let flush_context = self.flush_context();
+            let (reclaimed, marked) = flush_context.reclaim_pages()?;
+            warn!(
+                "Flush of WAL {} was interrupted, reclaimed {} unreferenced pages, \
+                 marked {} referenced pages allocated",
+                wal_id, reclaimed, marked
+            );
+            // Set again when the WAL is flushed, nothing can leak until then
+            flush_context.save_checkpoint(None)?;
+        }
+
         let mut wal_ids = get_all_wal_ids(self.dir.to_path_buf().join(WAL_DIR_NAME));
         for id in &wal_ids {
-            if *id > self.meta.read().unwrap().current_wal_id {
+            if *id > self.meta.read().unwrap().current_wal_id && !self.read_only {
                 let wal_file_path = self.wal_file_path(*id);
                 remove_file_if_exists(wal_file_path.as_path())?;
             }
         }
         wal_ids.retain(|&id| id <= self.meta.read().unwrap().current_wal_id);
-        let key_size = self.key_size as usize;
-        for wal_id in wal_ids {
-            let wal_file_path = self.wal_file_path(wal_id);
-            let wal = WAL::open(wal_file_path.as_path(), self.opts.wal_options.fsync)?;
-            if wal_id == self.meta.read().unwrap().current_wal_id {
-                let mut current_buffer = self.current_buffer.write().unwrap();
-                wal.replay(|batch_payload| {
-                    let mut batch_offset = 0;
-                    while batch_offset < batch_payload.len() {
-                        let entry_len = u32::from_le_bytes(
-                            batch_payload[batch_offset..batch_offset + 4]
-                                .try_into()
-                                .unwrap(),
-                        ) as usize;
-                        batch_offset += 4;
-                        let entry_payload = &batch_payload[batch_offset..batch_offset + entry_len];
-                        batch_offset += entry_len;
-
-                        if entry_len > key_size {
-                            // Put operation
-                            let key = entry_payload[..key_size].to_vec();
-                            let value = entry_payload[key_size..].to_vec();
-                            current_buffer.insert(key, KVOp::Put { value });
-                        } else {
-                            // Delete operation
-                            let key = entry_payload[..key_size].to_vec();
-                            current_buffer.insert(key, KVOp::Del {});
-                        }
-                    }
-                })?;
-            } else {
-                let mut buffer: HashMap<Vec<u8>, KVOp> = HashMap::new();
-                // replay, split each record by key_size
-                wal.replay(|batch_payload| {
-                    let mut batch_offset = 0;
-                    while batch_offset < batch_payload.len() {
-                        let entry_len = u32::from_le_bytes(
-                            batch_payload[batch_offset..batch_offset + 4]
-                                .try_into()
-                                .unwrap(),
-                        ) as usize;
-                        batch_offset += 4;
-                        let entry_payload = &batch_payload[batch_offset..batch_offset + entry_len];
-                        batch_offset += entry_len;
-
-                        if entry_len > key_size {
-                            // Put operation
-                            let key = entry_payload[..key_size].to_vec();
-                            let value = entry_payload[key_size..].to_vec();
-                            buffer.insert(key, KVOp::Put { value });
-                        } else {
-                            // Delete operation
-                            let key = entry_payload[..key_size].to_vec();
-                            buffer.insert(key, KVOp::Del {});
+        wal_ids.sort_unstable();
+        let current_wal_id = self.meta.read().unwrap().current_wal_id;
+        let older_ids: Vec<u64> = wal_ids
+            .iter()
+            .copied()
+            .filter(|&id| id != current_wal_id)
+            .collect();
+        let replayed_count = AtomicUsize::new(0);
+        let report_progress = |wal_id| {
+            if let Some(callback) = &self.opts.wal_options.recovery_progress {
+                callback(RecoveryProgress {
+                    wal_id,
+                    replayed: replayed_count.fetch_add(1, Ordering::Relaxed) + 1,
+                    total: wal_ids.len(),
+                });
+            }
+        };
+
+        // Older WALs are decoded in parallel into their own buffers, the current one meanwhile
+        let next_older = AtomicUsize::new(0);
+        let threads = self.opts.wal_options.recovery_threads.max(1).min(older_ids.len());
+        let (mut older, current) = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut replayed = Vec::new();
+                        while let Some(&wal_id) =
+                            older_ids.get(next_older.fetch_add(1, Ordering::Relaxed))
+                        {
+                            replayed.push((wal_id, self.replay_wal_file(wal_id)));
+                            report_progress(wal_id);
                         }
-                    }
-                })?;
-                self.flushing_buffers.write().unwrap().push(FlushingBuffer {
-                    buffer,
-                    wal_path: wal_file_path,
+                        replayed
+                    })
                 })
+                .collect();
+            let current = wal_ids.contains(&current_wal_id).then(|| {
+                let current_wal = self.current_wal.read().unwrap();
+                let key_size = self.key_layout.key_size();
+                let replayed = ReplayedWAL::replay(&current_wal, &self.opts, key_size);
+                report_progress(current_wal_id);
+                (current_wal_id, replayed.map_err(KVError::from))
+            });
+            let older: Vec<_> = workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("WAL replay thread panicked"))
+                .collect();
+            (older, current)
+        });
+
+        // Merge in write order, so newer buffers shadow older ones
+        older.sort_unstable_by_key(|(wal_id, _)| *wal_id);
+        let mut last_seq = self.meta.read().unwrap().last_seq;
+        for (wal_id, replayed) in older.into_iter().chain(current) {
+            let replayed = replayed?;
+            self.check_replay(wal_id, &replayed.result)?;
+            last_seq = last_seq.max(replayed.last_seq);
+            if wal_id == current_wal_id {
+                // Drop a torn batch at the tail, so new records are not appended after it
+                if !self.read_only {
+                    let mut current_wal = self.current_wal.write().unwrap();
+                    // Under `Fail` a corrupt record has failed the open above, what is
+                    // left to drop is torn
+                    current_wal.truncate(replayed.result.valid_len)?;
+                }
+                self.current_buffer_size
+                    .store(buffer_size(&replayed.buffer), Ordering::Relaxed);
+                *self.current_buffer.write().unwrap() = replayed.buffer;
+            } else {
+                self.flushing_buffers.write().unwrap().push(Arc::new(FlushingBuffer {
+                    size: buffer_size(&replayed.buffer),
+                    buffer: replayed.buffer,
+                    wal_id,
+                    wal_path: self.wal_file_path(wal_id),
+                }));
             }
         }
+        self.last_seq.store(last_seq, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replay a WAL file before the current one into a new buffer
+    fn replay_wal_file(&self, wal_id: u64) -> Result<ReplayedWAL, KVError> {
+        let wal_file_path = self.wal_file_path(wal_id);
+        let wal = if self.read_only {
+            WAL::open_read_only(wal_file_path.as_path())?
+        } else {
+            WAL::open(wal_file_path.as_path())?
+        };
+        Ok(ReplayedWAL::replay(&wal, &self.opts, self.key_layout.key_size())?)
+    }
+
+    /// Apply the recovery policy to a replayed WAL
+    fn check_replay(&self, wal_id: u64, result: &ReplayResult) -> Result<(), KVError> {
+        let Some(offset) = result.corrupt_offset else {
+            return Ok(());
+        };
+        match self.opts.wal_options.recovery_policy {
+            WALRecoveryPolicy::TruncateTail => warn!(
+                "WAL {} has a corrupt record at offset {}, dropping it and every later record",
+                wal_id, offset
+            ),
+            WALRecoveryPolicy::SkipCorrupt => warn!(
+                "WAL {} has {} corrupt records, skipped, the first at offset {}",
+                wal_id, result.skipped, offset
+            ),
+            WALRecoveryPolicy::Fail => return Err(KVError::CorruptedWAL { wal_id, offset }),
+        }
         Ok(())
     }
 
+    /// Sequence number of the last written batch, 0 if nothing was written yet
+    pub fn last_sequence(&self) -> u64 {
+        self.last_seq.load(Ordering::Relaxed)
+    }
+
+    /// How often and how long writes were held back by `WriteStallOptions`
+    pub fn write_stall_stats(&self) -> WriteStallStats {
+        self.write_stall.stats(self.pending())
+    }
+
+    /// Estimated memory taken by the buffers of written data
+    pub fn buffer_usage(&self) -> BufferUsage {
+        let flushing_buffers = self.flushing_buffers.read().unwrap();
+        BufferUsage {
+            current_bytes: self.current_buffer_size.load(Ordering::Relaxed),
+            flushing_bytes: flushing_buffers.iter().map(|buffer| buffer.size).sum(),
+            flushing_buffers: flushing_buffers.len(),
+        }
+    }
+
+    fn pending(&self) -> Pending {
+        let usage = self.buffer_usage();
+        Pending {
+            buffers: usage.flushing_buffers,
+            bytes: usage.current_bytes + usage.flushing_bytes,
+        }
+    }
+
     /// Single put operation
     pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), KVError> {
-        // Wrap into a single-op batch and call do_batch
-        let batch = Batch {
-            ops: vec![(key, KVOp::Put { value })],
-        };
+        let mut batch = Batch::new();
+        batch.put(key, value);
         self.batch(batch)
     }
 
     /// Single delete operation
     pub fn delete(&self, key: Vec<u8>) -> Result<(), KVError> {
-        // Wrap into a single-op batch and call do_batch
-        let batch = Batch {
-            ops: vec![(key, KVOp::Del {})],
-        };
+        let mut batch = Batch::new();
+        batch.delete(key);
         self.batch(batch)
     }
 
-    /// Batch put/delete
+    /// Batch put/delete, either all operations are applied or none.
+    ///
+    /// Keys and values are validated before anything is written, an invalid one fails
+    /// the whole batch.
+    /// Batches of concurrent callers are committed together with one WAL append and fsync.
+    /// `Batch::durability` overrides `WALOptions::durability` for a single batch.
+    /// Writes are slowed down or stopped while flushing falls behind, see `WriteStallOptions`.
     pub fn batch(&self, batch: Batch) -> Result<(), KVError> {
-        let mut wal_with_write_lock = self.current_wal.write().unwrap();
-        let mut flush_buffer = false;
-        let mut pre_wal_path = None;
+        if self.read_only {
+            return Err(KVError::ReadOnly);
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if let Some(Durability::Periodic(_)) = batch.durability
+            && !matches!(self.opts.wal_options.durability, Durability::Periodic(_))
+        {
+            // No sync thread runs, the batch would never be synced
+            return Err(KVError::InvalidOptions(
+                "Durability::Periodic requires WALOptions::durability to be Periodic".to_string(),
+            ));
+        }
+        if batch.ops.iter().any(|(key, _)| !self.key_layout.is_valid(key)) {
+            return Err(KVError::InvalidKeyLength);
+        }
+        if batch.ops.iter().any(|(_, op)| match op {
+            KVOp::Put { value } => value.len() > MAX_VALUE_SIZE,
+            KVOp::Del {} => false,
+        }) {
+            return Err(KVError::ValueTooLarge);
+        }
+        if batch.encoded_len() > MAX_BATCH_SIZE {
+            return Err(KVError::BatchTooLarge);
+        }
+        self.write_stall
+            .throttle(&self.flush_state, || self.pending())?;
+        self.group_commit
+            .commit(batch, |batches| self.write_batches(batches))
+    }
 
-        // Compute total payload size
-        // Total payload length: each entry has 4 bytes representing entry length + entry data
-        let total_size: usize = batch
-            .ops
+    /// Write a group of batches to the WAL as one record each, then apply them in order
+    fn write_batches(&self, batches: Vec<Batch>) -> Result<(), KVError> {
+        let mut wal_with_write_lock = self.current_wal.write().unwrap();
+        if wal_with_write_lock.is_poisoned() {
+            // A failed append is still at its tail, new batches go to the next WAL
+            let mut buffer_with_write_lock = self.current_buffer.write().unwrap();
+            self.rotate_buffer(&mut wal_with_write_lock, &mut buffer_with_write_lock)?;
+            self.trigger_async_flush();
+        }
+        let first_seq = self.last_seq.load(Ordering::Relaxed) + 1;
+        let payloads: Vec<Vec<u8>> = batches
             .iter()
-            .map(|(key, op)| {
-                return match op {
-                    KVOp::Put { value } => key.len() + value.len(),
-                    KVOp::Del {} => key.len(),
-                } + 4; // entry_len
-            })
-            .sum();
+            .zip(first_seq..)
+            .map(|(batch, seq)| batch.encode(seq))
+            .collect();
 
-        // Payload = [total_size 4 bytes] + data
-        let mut payload = Vec::with_capacity(total_size + 4);
-        payload.extend_from_slice(&(total_size as u32).to_le_bytes());
+        // Write to WAL, synced once for the strongest durability in the group
+        let durability = batches
+            .iter()
+            .map(|batch| batch.durability.unwrap_or(self.opts.wal_options.durability))
+            .max_by_key(|durability| durability.strength())
+            .unwrap_or(self.opts.wal_options.durability);
+        let size =
+            wal_with_write_lock.write_records(&payloads, &self.wal_compressor, durability)?;
+        self.last_seq
+            .store(first_seq + batches.len() as u64 - 1, Ordering::Relaxed);
 
-        // Append each op to payload
-        for (key, op) in &batch.ops {
-            match op {
-                KVOp::Put { value } => {
-                    if key.len() != self.key_size as usize {
-                        return Err(KVError::InvalidKeyLength);
+        // Update in-memory buffer
+        {
+            let mut buffer_with_write_lock = self.current_buffer.write().unwrap();
+            let mut buffer_size = self.current_buffer_size.load(Ordering::Relaxed);
+            for batch in batches {
+                for (key, op) in batch.ops {
+                    buffer_size += entry_size(&key, &op);
+                    if let Some(replaced) = buffer_with_write_lock.get(&key) {
+                        buffer_size -= entry_size(&key, replaced);
                     }
-                    let entry_len = (key.len() + value.len()) as u32;
-                    payload.extend_from_slice(&entry_len.to_le_bytes()); // total length
-                    payload.extend_from_slice(key);
-                    payload.extend_from_slice(value);
+                    buffer_with_write_lock.insert(key, op);
                 }
-                KVOp::Del {} => {
-                    if key.len() != self.key_size as usize {
-                        return Err(KVError::InvalidKeyLength);
-                    }
-                    payload.extend_from_slice(&self.key_size.to_le_bytes()); // key length only
-                    payload.extend_from_slice(key);
+            }
+            self.current_buffer_size.store(buffer_size, Ordering::Relaxed);
+
+            if size > self.wal_flush_size as u64
+                || buffer_size > self.opts.wal_options.max_buffer_memory
+            {
+                // The batch is already durable, a failed rotation is retried on the next write
+                match self.rotate_buffer(&mut wal_with_write_lock, &mut buffer_with_write_lock) {
+                    Ok(_) => self.trigger_async_flush(),
+                    Err(e) => error!("Failed to rotate WAL: {:?}", e),
                 }
             }
         }
 
-        // Write to WAL
-        let size = wal_with_write_lock.write_record(payload)?;
+        Ok(())
+    }
 
-        if size > self.wal_flush_size as u64 {
-            pre_wal_path = Some(self.wal_file_path(self.current_wal_id.load(Ordering::Relaxed)));
-            let next_wal_id = self.current_wal_id.load(Ordering::Relaxed) + 1;
-            let next_wal_path = self.wal_file_path(next_wal_id);
-            if !self.opts.wal_options.fsync {
-                wal_with_write_lock.flush()?;// at least flush here
-            }
-            *wal_with_write_lock = WAL::open(next_wal_path.as_path(), self.opts.wal_options.fsync)?;
-            self.current_wal_id.fetch_add(1, Ordering::Relaxed);
-            flush_buffer = true;
-        }
+    /// Move the current buffer with its WAL to the flushing buffers, returns the id of that WAL
+    fn rotate_buffer(
+        &self,
+        wal: &mut WAL,
+        buffer: &mut HashMap<Vec<u8>, KVOp>,
+    ) -> Result<u64, KVError> {
+        let pre_wal_id = self.rotate_wal(wal)?;
+        self.flushing_buffers.write().unwrap().push(Arc::new(FlushingBuffer {
+            buffer: std::mem::take(buffer),
+            size: self.current_buffer_size.swap(0, Ordering::Relaxed),
+            wal_id: pre_wal_id,
+            wal_path: self.wal_file_path(pre_wal_id),
+        }));
+        Ok(pre_wal_id)
+    }
 
-        // Update in-memory buffer
+    /// Switch to the next WAL file and record it in kv.meta, returns the id of the previous WAL
+    fn rotate_wal(&self, wal: &mut WAL) -> Result<u64, KVError> {
+        let wal_id = self.current_wal_id.load(Ordering::Relaxed);
+        let next_wal_id = wal_id + 1;
+        // Batches written without a sync may still be in the page cache
+        wal.sync()?;
+        let next_wal = WAL::open(self.wal_file_path(next_wal_id).as_path())?;
         {
-            let mut buffer_with_write_lock = self.current_buffer.write().unwrap();
-            buffer_with_write_lock.extend(batch.ops);
-
-            if flush_buffer {
-                let pre_buffer =
-                    std::mem::replace(&mut *buffer_with_write_lock, Default::default());
-                self.flushing_buffers.write().unwrap().push(FlushingBuffer {
-                    buffer: pre_buffer,
-                    wal_path: pre_wal_path.unwrap(),
-                });
-                self.trigger_async_flush();
-            }
+            // WAL files above current_wal_id are deleted on load, so persist it before writing
+            let mut meta = self.meta.write().unwrap();
+            let mut next_meta = meta.clone();
+            next_meta.current_wal_id = next_wal_id;
+            next_meta.last_seq = self.last_seq.load(Ordering::Relaxed);
+            next_meta.save_to_file(self.dir.join(KV_META_FILE_NAME))?;
+            *meta = next_meta;
         }
-
-        Ok(())
+        *wal = next_wal;
+        self.current_wal_id.store(next_wal_id, Ordering::Relaxed);
+        Ok(wal_id)
     }
 
     fn wal_file_path(&self, wal_id: u64) -> PathBuf {
         wal_file_path(self.dir.join(WAL_DIR_NAME).as_path(), wal_id)
     }
 
-    pub fn trigger_async_flush(&self) {
-        {
-            if !self.flush_lock.try_lock().is_ok() {
-                return;
-            }
+    fn flush_context(&self) -> FlushContext {
+        FlushContext {
+            state: self.flush_state.clone(),
+            meta: self.meta.clone(),
+            meta_path: self.dir.join(KV_META_FILE_NAME),
+            wal_dir: self.dir.join(WAL_DIR_NAME),
+            flushing_buffers: self.flushing_buffers.clone(),
+            level_page_bitmap: self.level_page_bitmap.clone(),
+            buckets_index: self.buckets_index.clone(),
+            key_layout: self.key_layout,
+            cache: self.cache.clone(),
         }
+    }
 
-        let flushing_buffers = self.flushing_buffers.clone();
-        let flush_lock = self.flush_lock.clone();
-        let level_page_bitmap = self.level_page_bitmap.clone();
-        let buckets_index = self.buckets_index.clone();
-
-        thread::spawn(move || {
-            let _guard = flush_lock.lock().unwrap();
-            loop {
-                {
-                    let mut flushing_buffers_with_read_lock = flushing_buffers.read().unwrap();
-                    if flushing_buffers_with_read_lock.is_empty() {
-                        return;
-                    }
-                    if let Some(flushing_buffer) = flushing_buffers_with_read_lock.get(0) {
-                        for (key, op) in &flushing_buffer.buffer {
-                            match op {
-                                KVOp::Put { value } => {
-                                    let value_len = value.len();
-                                    let data_id = loop {
-                                        match level_page_bitmap.write(value.clone()) {
-                                            Ok(id) => break id,
-                                            Err(e) => {
-                                                error!(
-                                                    "Failed to write level_page_bitmap, retrying: {:?}",
-                                                    e
-                                                );
-                                                sleep(Duration::from_secs(1));
-                                            }
-                                        }
-                                    };
-                                    let data_info = DataInfo {
-                                        data_id,
-                                        data_len: value_len as u32,
-                                    };
-                                    loop {
-                                        match buckets_index.put(key.clone(), data_info.clone()) {
-                                            Ok(_) => break,
-                                            Err(e) => {
-                                                error!(
-                                                    "Failed to put into buckets_index, retrying: {:?}",
-                                                    e
-                                                );
-                                                sleep(Duration::from_secs(1));
-                                            }
-                                        }
-                                    }
-                                }
-                                KVOp::Del { .. } => loop {
-                                    match buckets_index.del(key) {
-                                        Ok(data_info) => {
-                                            if let Some(data_info) = data_info {
-                                                let data_id = data_info.data_id;
-                                                loop {
-                                                    match level_page_bitmap.free(data_id) {
-                                                        Ok(_) => break,
-                                                        Err(err) => {
-                                                            error!("free data_id error: {:?}", err);
-                                                            sleep(Duration::from_secs(1));
-                                                            continue;
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            break;
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "Failed to del key from buckets_index, retrying: {:?}",
-                                                e
-                                            );
-                                            sleep(Duration::from_secs(5));
-                                            continue;
-                                        }
-                                    }
-                                },
-                            }
-                        }
+    pub fn trigger_async_flush(&self) {
+        if self.read_only {
+            return;
+        }
+        self.flush_state.start(|| self.flush_context());
+    }
 
-                        loop {
-                            match remove_file_if_exists(flushing_buffer.wal_path.clone()) {
-                                Ok(_) => break,
-                                Err(e) => {
-                                    error!("Failed to remove WAL file, retrying: {:?}", e);
-                                    sleep(Duration::from_secs(5));
-                                }
-                            }
-                        }
-                    }
-                }
-                let mut flushing_buffers_with_write_lock = flushing_buffers.write().unwrap();
-                flushing_buffers_with_write_lock.remove(0);
+    /// Rotate the current WAL and buffer into the flushing buffers and start flushing them.
+    /// The returned handle waits until everything written before this call is persisted.
+    pub fn flush_async(&self) -> Result<FlushHandle, KVError> {
+        if self.read_only {
+            return Err(KVError::ReadOnly);
+        }
+        let wal_id = {
+            let mut wal_with_write_lock = self.current_wal.write().unwrap();
+            let mut buffer_with_write_lock = self.current_buffer.write().unwrap();
+            if buffer_with_write_lock.is_empty() {
+                // Nothing new, wait for the buffers already being flushed
+                self.flushing_buffers.read().unwrap().last().map(|b| b.wal_id)
+            } else {
+                Some(self.rotate_buffer(&mut wal_with_write_lock, &mut buffer_with_write_lock)?)
             }
-        });
+        };
+        if wal_id.is_some() {
+            self.trigger_async_flush();
+        }
+        Ok(FlushHandle::new(self.flush_state.clone(), wal_id))
+    }
+
+    /// Flush the current buffer and block until everything written before this call is persisted
+    pub fn flush(&self) -> Result<(), KVError> {
+        self.flush_async()?.wait();
+        Ok(())
     }
 
     /// Read key-value
@@ -496,7 +1170,8 @@ impl KV {
         }
 
         let flushing_buffers_with_read_lock = self.flushing_buffers.read().unwrap();
-        for flushing_buffer in flushing_buffers_with_read_lock.iter() {
+        // Newest first, a later buffer shadows an earlier one
+        for flushing_buffer in flushing_buffers_with_read_lock.iter().rev() {
             if let Some(op) = flushing_buffer.buffer.get(key) {
                 match op {
                     KVOp::Put { value } => return Ok(Some(value.clone())),
@@ -507,22 +1182,81 @@ impl KV {
             }
         }
 
-        if let Some(data_info) = self.buckets_index.get(key)? {
-            // Read corresponding LevelPageBitmap page
-            let mut data = self.level_page_bitmap.read(data_info.data_id)?;
-            data.truncate(data_info.data_len as usize);
+        if let Some(value) = self.cache.get(key) {
+            return Ok(Some(value));
+        }
+
+        // The flushing buffers read lock is still held here, so the flush thread
+        // cannot drop a buffer that would make the value read below stale
+        if let Some(data_info) = self.buckets_index.get(&self.key_layout.encode(key))? {
+            // Read corresponding LevelPageBitmap pages, unless the value is inline
+            let mut data = data_info.read(&self.level_page_bitmap)?;
+            if self.key_layout.is_long(key) {
+                // A long key is stored in front of its value, another key with the
+                // same fingerprint would not match it
+                if !data.starts_with(key) {
+                    return Ok(None);
+                }
+                data.drain(..key.len());
+            }
+            self.cache.insert(key.to_vec(), data.clone());
             Ok(Some(data))
         } else {
             Ok(None)
         }
     }
+
+    /// Iterate over all live key-value pairs, in no particular order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self, true)
+    }
+
+    /// Iterate over all live keys, in no particular order
+    pub fn keys(&self) -> Keys<'_> {
+        Keys::new(self)
+    }
+
+    /// Shut down the KV and release all file handles, reporting errors that `Drop` can only log.
+    ///
+    /// The buffer being flushed is finished, remaining buffers are flushed after reopening.
+    pub fn close(mut self) -> Result<(), KVError> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), KVError> {
+        if std::mem::replace(&mut self.closed, true) {
+            return Ok(());
+        }
+        let joined = self.flush_state.close();
+        let sync_joined = match self.periodic_sync.as_mut() {
+            Some(periodic_sync) => periodic_sync.stop(),
+            None => Ok(()),
+        };
+        // Writes without fsync are only in the page cache until now
+        if !self.read_only {
+            self.current_wal.read().unwrap().sync()?;
+        }
+        joined.map_err(|_| KVError::Other("flush thread panicked".to_string()))?;
+        sync_joined.map_err(|_| KVError::Other("WAL sync thread panicked".to_string()))
+    }
+}
+
+impl Drop for KV {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!("Failed to close KV: {:?}", e);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::kv::utils::random_bytes32;
-    use std::time::Duration;
+    use std::collections::{BTreeMap, HashSet};
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
     use tempfile::tempdir;
 
     #[test]
@@ -549,12 +1283,11 @@ mod tests {
         let value = b"persisted data".to_vec();
 
         kv.put(key.clone(), value.clone()).unwrap();
-
-        // Trigger flush
-        // kv.trigger_async_flush();
-
-        // Wait for flush thread to execute
-        std::thread::sleep(Duration::from_secs(1));
+        kv.flush().unwrap();
+        assert!(kv.current_buffer.read().unwrap().is_empty());
+        assert!(kv.flushing_buffers.read().unwrap().is_empty());
+        assert!(kv.buckets_index.get(&key).unwrap().is_some());
+        drop(kv);
 
         let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
 
@@ -562,6 +1295,1101 @@ mod tests {
         assert_eq!(result, Some(value));
     }
 
+    #[test]
+    fn test_kv_flush_async() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        // Nothing to flush
+        let handle = kv.flush_async().unwrap();
+        assert!(handle.is_done());
+        handle.wait();
+
+        let keys: Vec<Vec<u8>> = (0..100).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), b"value".to_vec()).unwrap();
+        }
+        let handle = kv.flush_async().unwrap();
+        assert!(kv.current_buffer.read().unwrap().is_empty());
+        handle.wait();
+
+        assert!(kv.flushing_buffers.read().unwrap().is_empty());
+        for key in &keys {
+            assert!(kv.buckets_index.get(key).unwrap().is_some());
+        }
+        // Only the current WAL is left
+        let wal_ids = get_all_wal_ids(dir.path().join(WAL_DIR_NAME));
+        assert_eq!(wal_ids, vec![kv.current_wal_id.load(Ordering::Relaxed)]);
+    }
+
+    fn dir_contents(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut contents = BTreeMap::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                contents.extend(dir_contents(&path));
+            } else {
+                contents.insert(path.clone(), fs::read(&path).unwrap());
+            }
+        }
+        contents
+    }
+
+    #[test]
+    fn test_kv_open_read_only() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = 1;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        let keys: Vec<Vec<u8>> = (0..50).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), key.clone()).unwrap();
+        }
+        kv.flush().unwrap();
+        kv.delete(keys[0].clone()).unwrap();
+        kv.put(keys[1].clone(), b"updated".to_vec()).unwrap();
+        let current_wal_id = kv.current_wal_id.load(Ordering::Relaxed);
+        drop(kv);
+
+        // A stray WAL and a torn tail, both cleaned up when opened for writing
+        let wal_dir = dir.path().join(WAL_DIR_NAME);
+        fs::write(wal_file_path(&wal_dir, current_wal_id + 1), b"stray").unwrap();
+        let mut current_wal = fs::read(wal_file_path(&wal_dir, current_wal_id)).unwrap();
+        current_wal.extend_from_slice(&[0xff; 7]);
+        fs::write(wal_file_path(&wal_dir, current_wal_id), current_wal).unwrap();
+        let before = dir_contents(dir.path());
+
+        let kv = KV::open_read_only(dir.path()).unwrap();
+        let other = KV::open_read_only(dir.path()).unwrap();
+        assert!(matches!(
+            KV::new(dir.path(), KVOptions::default()),
+            Err(KVError::Locked)
+        ));
+
+        assert_eq!(kv.get(&keys[0]).unwrap(), None);
+        assert_eq!(kv.get(&keys[1]).unwrap(), Some(b"updated".to_vec()));
+        for key in &keys[2..] {
+            assert_eq!(kv.get(key).unwrap(), Some(key.clone()));
+        }
+        assert_eq!(kv.iter().count(), keys.len() - 1);
+
+        assert!(matches!(
+            kv.put(keys[2].clone(), b"value".to_vec()),
+            Err(KVError::ReadOnly)
+        ));
+        assert!(matches!(kv.flush(), Err(KVError::ReadOnly)));
+        kv.close().unwrap();
+        drop(other);
+
+        assert_eq!(dir_contents(dir.path()), before);
+    }
+
+    #[test]
+    fn test_kv_directory_locked() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert!(matches!(
+            KV::new(dir.path(), KVOptions::default()),
+            Err(KVError::Locked)
+        ));
+
+        kv.close().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        drop(kv);
+        KV::new(dir.path(), KVOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn test_kv_close_with_pending_flushes() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = 1;
+        opts.wal_options.durability = Durability::None;
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..200).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), key.clone()).unwrap();
+        }
+        // A handle taken before close must not block forever on abandoned buffers
+        let handle = kv.flush_async().unwrap();
+        kv.close().unwrap();
+        handle.wait();
+
+        let kv = KV::new(dir.path(), opts).unwrap();
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(key.clone()));
+        }
+        kv.flush().unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert!(kv.flushing_buffers.read().unwrap().is_empty());
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn test_kv_rotates_by_buffer_memory() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = u32::MAX;
+        opts.wal_options.max_buffer_memory = 3 * 4096;
+        let kv = KV::new(dir.path(), opts).unwrap();
+
+        let key = random_bytes32().to_vec();
+        let entry_size = ENTRY_OVERHEAD + 32 + 4096;
+        kv.put(key.clone(), vec![0u8; 4096]).unwrap();
+        assert_eq!(kv.buffer_usage().current_bytes, entry_size);
+        kv.put(key.clone(), vec![1u8; 4096]).unwrap();
+        assert_eq!(kv.buffer_usage().current_bytes, entry_size);
+        kv.delete(key.clone()).unwrap();
+        assert_eq!(kv.buffer_usage().current_bytes, ENTRY_OVERHEAD + 32);
+
+        // Zeros compress to almost nothing, only the buffer memory triggers the rotation
+        for _ in 0..3 {
+            kv.put(random_bytes32().to_vec(), vec![0u8; 4096]).unwrap();
+        }
+        assert_eq!(kv.current_wal_id.load(Ordering::Relaxed), 1);
+        assert_eq!(kv.buffer_usage().current_bytes, 0);
+        kv.flush().unwrap();
+        assert_eq!(kv.buffer_usage(), BufferUsage::default());
+    }
+
+    #[test]
+    fn test_kv_write_stall() {
+        // Leave a buffer to flush without starting the flush thread
+        fn rotate_without_flush(kv: &KV) {
+            let mut wal = kv.current_wal.write().unwrap();
+            let mut buffer = kv.current_buffer.write().unwrap();
+            kv.rotate_buffer(&mut wal, &mut buffer).unwrap();
+        }
+
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.write_stall_options.soft_pending_buffers = 1;
+        opts.write_stall_options.hard_pending_buffers = 2;
+        opts.write_stall_options.stop_behavior = StopBehavior::Fail;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        let key = random_bytes32().to_vec();
+
+        kv.put(key.clone(), b"v1".to_vec()).unwrap();
+        rotate_without_flush(&kv);
+        kv.put(key.clone(), b"v2".to_vec()).unwrap();
+        rotate_without_flush(&kv);
+        assert!(matches!(kv.put(key.clone(), b"v3".to_vec()), Err(KVError::WriteStall)));
+        let stats = kv.write_stall_stats();
+        assert_eq!(stats.condition, WriteStallCondition::Stopped);
+        assert_eq!(stats.delayed_writes, 1);
+        assert_eq!(stats.rejected_writes, 1);
+        kv.flush().unwrap();
+        assert_eq!(kv.write_stall_stats().condition, WriteStallCondition::Normal);
+        kv.put(key.clone(), b"v3".to_vec()).unwrap();
+        drop(kv);
+
+        // Blocked until the flush thread catches up
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.write_stall_options.hard_pending_bytes = 1;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        kv.put(key.clone(), b"v1".to_vec()).unwrap();
+        rotate_without_flush(&kv);
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| kv.put(key.clone(), b"v2".to_vec()));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!writer.is_finished());
+            kv.trigger_async_flush();
+            writer.join().unwrap().unwrap();
+        });
+        let stats = kv.write_stall_stats();
+        assert_eq!(stats.stopped_writes, 1);
+        assert!(stats.stall_time >= Duration::from_millis(50));
+        assert_eq!(kv.get(&key).unwrap(), Some(b"v2".to_vec()));
+        drop(kv);
+
+        // Blocked writers, rotations and the flush thread never wait on each other's locks
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = 1; // Rotate and flush on every write
+        opts.write_stall_options.hard_pending_buffers = 1;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        let keys: Vec<Vec<u8>> = (0..100).map(|_| random_bytes32().to_vec()).collect();
+        thread::scope(|scope| {
+            for chunk in keys.chunks(25) {
+                let kv = &kv;
+                scope.spawn(move || {
+                    for key in chunk {
+                        kv.put(key.clone(), key.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn test_kv_durability_levels() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.durability = Durability::Periodic(10);
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..4).map(|_| random_bytes32().to_vec()).collect();
+        kv.put(keys[0].clone(), b"periodic".to_vec()).unwrap();
+        for (key, durability) in keys[1..]
+            .iter()
+            .zip([Durability::None, Durability::DataSync, Durability::Sync])
+        {
+            let mut batch = Batch::new();
+            batch.put(key.clone(), b"override".to_vec()).durability(durability);
+            kv.batch(batch).unwrap();
+        }
+        // Let the sync thread run at least once
+        thread::sleep(Duration::from_millis(30));
+        kv.close().unwrap();
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(b"periodic".to_vec()));
+        for key in &keys[1..] {
+            assert_eq!(kv.get(key).unwrap(), Some(b"override".to_vec()));
+        }
+        assert_eq!(kv.last_sequence(), 4);
+        drop(kv);
+
+        opts.wal_options.durability = Durability::Periodic(0);
+        assert!(matches!(
+            KV::new(dir.path(), opts.clone()),
+            Err(KVError::InvalidOptions(_))
+        ));
+        opts.wal_options.durability = Durability::Sync;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        let mut batch = Batch::new();
+        batch.put(keys[0].clone(), b"unsynced".to_vec()).durability(Durability::Periodic(10));
+        assert!(matches!(kv.batch(batch), Err(KVError::InvalidOptions(_))));
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(b"periodic".to_vec()));
+    }
+
+    #[test]
+    fn test_kv_cache_populated_and_invalidated() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = 1; // Rotate and flush on every write
+        let kv = KV::new(dir.path(), opts).unwrap();
+
+        let key = random_bytes32().to_vec();
+
+        // Not populated on flush
+        kv.put(key.clone(), b"v1".to_vec()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.cache.get(&key), None);
+
+        // Populated on read miss
+        assert_eq!(kv.get(&key).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(kv.cache.get(&key), Some(b"v1".to_vec()));
+
+        // Overwrite replaces the cached value on flush
+        kv.put(key.clone(), b"v2".to_vec()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.cache.get(&key), Some(b"v2".to_vec()));
+        assert_eq!(kv.get(&key).unwrap(), Some(b"v2".to_vec()));
+
+        // Delete invalidates the cached value
+        kv.delete(key.clone()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.cache.get(&key), None);
+        assert_eq!(kv.get(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_kv_iter_merges_buffers_and_disk() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = 1; // Rotate and flush on every write
+        let kv = KV::new(dir.path(), opts).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..20).map(|_| random_bytes32().to_vec()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            kv.put(key.clone(), vec![i as u8; 10]).unwrap();
+        }
+        kv.flush().unwrap();
+
+        // Buffered operations, left in the current buffer
+        let mut kv_opts = KVOptions::default();
+        kv_opts.wal_options.flush_size = u32::MAX;
+        drop(kv);
+        let kv = KV::new(dir.path(), kv_opts).unwrap();
+        kv.put(keys[0].clone(), b"overwritten".to_vec()).unwrap();
+        kv.delete(keys[1].clone()).unwrap();
+        let new_key = random_bytes32().to_vec();
+        kv.put(new_key.clone(), b"new".to_vec()).unwrap();
+
+        let mut expected: HashMap<Vec<u8>, Vec<u8>> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.clone(), vec![i as u8; 10]))
+            .collect();
+        expected.insert(keys[0].clone(), b"overwritten".to_vec());
+        expected.remove(&keys[1]);
+        expected.insert(new_key, b"new".to_vec());
+
+        let items: Vec<(Vec<u8>, Vec<u8>)> = kv.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(items.len(), expected.len());
+        assert_eq!(items.into_iter().collect::<HashMap<_, _>>(), expected);
+
+        let mut all_keys: Vec<Vec<u8>> = kv.keys().map(|key| key.unwrap()).collect();
+        let mut expected_keys: Vec<Vec<u8>> = expected.into_keys().collect();
+        all_keys.sort();
+        expected_keys.sort();
+        assert_eq!(all_keys, expected_keys);
+    }
+
+    #[test]
+    fn test_kv_batch_applied_after_replay() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..3).map(|_| random_bytes32().to_vec()).collect();
+        kv.put(keys[2].clone(), b"old".to_vec()).unwrap();
+
+        let mut batch = Batch::new();
+        batch
+            .put(keys[0].clone(), b"a".to_vec())
+            .put(keys[1].clone(), b"b".to_vec())
+            .delete(keys[2].clone());
+        assert_eq!(batch.len(), 3);
+        kv.batch(batch).unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(kv.get(&keys[1]).unwrap(), Some(b"b".to_vec()));
+        assert_eq!(kv.get(&keys[2]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_kv_torn_batch_not_applied_after_replay() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        let committed = random_bytes32().to_vec();
+        kv.put(committed.clone(), b"committed".to_vec()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..3).map(|_| random_bytes32().to_vec()).collect();
+        let mut batch = Batch::new();
+        for key in &keys {
+            batch.put(key.clone(), b"torn".to_vec());
+        }
+        kv.batch(batch).unwrap();
+        drop(kv);
+
+        // Simulate a crash in the middle of writing the batch record
+        let wal_path = wal_file_path(dir.path().join(WAL_DIR_NAME).as_path(), 0);
+        let wal_len = fs::metadata(&wal_path).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&wal_path)
+            .unwrap()
+            .set_len(wal_len - 1)
+            .unwrap();
+
+        // A torn tail is not corruption, even under Fail
+        let mut opts = KVOptions::default();
+        opts.wal_options.recovery_policy = WALRecoveryPolicy::Fail;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        assert_eq!(kv.get(&committed).unwrap(), Some(b"committed".to_vec()));
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), None);
+        }
+
+        // The torn tail is dropped, so later batches are replayed as well
+        let after = random_bytes32().to_vec();
+        kv.put(after.clone(), b"after".to_vec()).unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert_eq!(kv.get(&committed).unwrap(), Some(b"committed".to_vec()));
+        assert_eq!(kv.get(&after).unwrap(), Some(b"after".to_vec()));
+    }
+
+    #[test]
+    fn test_kv_failed_wal_append_not_replayed() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.recovery_policy = WALRecoveryPolicy::Fail;
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        let keys: Vec<Vec<u8>> = (0..4).map(|_| random_bytes32().to_vec()).collect();
+
+        kv.put(keys[0].clone(), b"before".to_vec()).unwrap();
+        kv.current_wal.write().unwrap().fail_next_append(7, false);
+        assert!(kv.put(keys[1].clone(), b"failed".to_vec()).is_err());
+        kv.put(keys[2].clone(), b"after".to_vec()).unwrap();
+
+        // A failed append that cannot be removed moves writes to the next WAL
+        kv.current_wal.write().unwrap().fail_next_append(7, true);
+        assert!(kv.put(keys[1].clone(), b"failed".to_vec()).is_err());
+        kv.put(keys[3].clone(), b"rotated".to_vec()).unwrap();
+        assert_eq!(kv.current_wal_id.load(Ordering::Relaxed), 1);
+        drop(kv);
+
+        let kv = KV::new(dir.path(), opts).unwrap();
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(b"before".to_vec()));
+        assert_eq!(kv.get(&keys[1]).unwrap(), None);
+        assert_eq!(kv.get(&keys[2]).unwrap(), Some(b"after".to_vec()));
+        assert_eq!(kv.get(&keys[3]).unwrap(), Some(b"rotated".to_vec()));
+    }
+
+    #[test]
+    fn test_kv_corrupt_wal_recovery_policies() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        let keys: Vec<Vec<u8>> = (0..3).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), key.clone()).unwrap();
+        }
+        drop(kv);
+
+        // Flip the last payload byte of the second record
+        let wal_path = wal_file_path(dir.path().join(WAL_DIR_NAME).as_path(), 0);
+        let mut wal = fs::read(&wal_path).unwrap();
+        let first_len = u32::from_le_bytes(wal[8..12].try_into().unwrap()) as usize;
+        let second = 8 + 12 + first_len;
+        let second_len = u32::from_le_bytes(wal[second..second + 4].try_into().unwrap()) as usize;
+        wal[second + 12 + second_len - 1] ^= 0xff;
+        fs::write(&wal_path, &wal).unwrap();
+
+        let open = |policy| {
+            let mut opts = KVOptions::default();
+            opts.wal_options.recovery_policy = policy;
+            KV::new(dir.path(), opts)
+        };
+
+        let result = open(WALRecoveryPolicy::Fail);
+        assert!(matches!(
+            result,
+            Err(KVError::CorruptedWAL { wal_id: 0, offset }) if offset == second as u64
+        ));
+
+        let kv = open(WALRecoveryPolicy::SkipCorrupt).unwrap();
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(keys[0].clone()));
+        assert_eq!(kv.get(&keys[1]).unwrap(), None);
+        assert_eq!(kv.get(&keys[2]).unwrap(), Some(keys[2].clone()));
+        drop(kv);
+        assert_eq!(fs::read(&wal_path).unwrap(), wal);
+
+        let kv = open(WALRecoveryPolicy::TruncateTail).unwrap();
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(keys[0].clone()));
+        assert_eq!(kv.get(&keys[1]).unwrap(), None);
+        assert_eq!(kv.get(&keys[2]).unwrap(), None);
+        drop(kv);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), second as u64);
+    }
+
+    #[test]
+    fn test_kv_corrupt_wal_length_is_not_a_torn_tail() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        let keys: Vec<Vec<u8>> = (0..3).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), key.clone()).unwrap();
+        }
+        drop(kv);
+
+        // Make the length of the second record point past the end of the file
+        let wal_path = wal_file_path(dir.path().join(WAL_DIR_NAME).as_path(), 0);
+        let mut wal = fs::read(&wal_path).unwrap();
+        let first_len = u32::from_le_bytes(wal[8..12].try_into().unwrap()) as usize;
+        let second = 8 + 12 + first_len;
+        wal[second + 3] ^= 0x10;
+        fs::write(&wal_path, &wal).unwrap();
+
+        let open = |policy| {
+            let mut opts = KVOptions::default();
+            opts.wal_options.recovery_policy = policy;
+            KV::new(dir.path(), opts)
+        };
+        assert!(matches!(
+            open(WALRecoveryPolicy::Fail),
+            Err(KVError::CorruptedWAL { wal_id: 0, offset }) if offset == second as u64
+        ));
+        assert_eq!(fs::read(&wal_path).unwrap(), wal);
+
+        // The records after it cannot be found, even when skipping corrupt ones
+        let kv = open(WALRecoveryPolicy::SkipCorrupt).unwrap();
+        assert_eq!(kv.get(&keys[0]).unwrap(), Some(keys[0].clone()));
+        assert_eq!(kv.get(&keys[1]).unwrap(), None);
+        assert_eq!(kv.get(&keys[2]).unwrap(), None);
+        drop(kv);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), second as u64);
+    }
+
+    #[test]
+    fn test_kv_empty_value_is_not_a_delete() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        let key = random_bytes32().to_vec();
+        kv.put(key.clone(), Vec::new()).unwrap();
+        assert_eq!(kv.last_sequence(), 1);
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert_eq!(kv.get(&key).unwrap(), Some(Vec::new()));
+        assert_eq!(kv.last_sequence(), 1);
+    }
+
+    #[test]
+    fn test_kv_sequence_survives_flush() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        for _ in 0..3 {
+            kv.put(random_bytes32().to_vec(), b"value".to_vec()).unwrap();
+        }
+        kv.flush().unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert_eq!(kv.last_sequence(), 3);
+        let mut batch = Batch::new();
+        batch.put(random_bytes32().to_vec(), b"value".to_vec());
+        batch.delete(random_bytes32().to_vec());
+        kv.batch(batch).unwrap();
+        assert_eq!(kv.last_sequence(), 4);
+    }
+
+    #[test]
+    fn test_kv_concurrent_writers() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        let keys: Vec<Vec<u8>> = (0..400).map(|_| random_bytes32().to_vec()).collect();
+        thread::scope(|scope| {
+            for chunk in keys.chunks(50) {
+                let kv = &kv;
+                scope.spawn(move || {
+                    for key in chunk {
+                        kv.put(key.clone(), key.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(kv.last_sequence(), keys.len() as u64);
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(key.clone()));
+        }
+    }
+
+    #[test]
+    fn test_kv_replays_legacy_wal() {
+        let dir = tempdir().unwrap();
+        drop(KV::new(dir.path(), KVOptions::default()).unwrap());
+
+        // A WAL without header holding one batch of the untagged format: a put and a delete
+        let put_key = random_bytes32().to_vec();
+        let del_key = random_bytes32().to_vec();
+        let mut entries = Vec::new();
+        entries.extend_from_slice(&(32u32 + 5).to_le_bytes());
+        entries.extend_from_slice(&put_key);
+        entries.extend_from_slice(b"value");
+        entries.extend_from_slice(&32u32.to_le_bytes());
+        entries.extend_from_slice(&del_key);
+        let mut batch_payload = (entries.len() as u32).to_le_bytes().to_vec();
+        batch_payload.extend_from_slice(&entries);
+        let record = zstd::encode_all(batch_payload.as_slice(), 3).unwrap();
+        let mut wal = (record.len() as u32).to_le_bytes().to_vec();
+        wal.extend_from_slice(&record);
+        fs::write(wal_file_path(&dir.path().join(WAL_DIR_NAME), 0), wal).unwrap();
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert_eq!(kv.get(&put_key).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(kv.get(&del_key).unwrap(), None);
+        // New batches go to a WAL of the current version
+        assert_eq!(kv.current_wal_id.load(Ordering::Relaxed), 1);
+        let key = random_bytes32().to_vec();
+        kv.put(key.clone(), Vec::new()).unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        assert_eq!(kv.get(&put_key).unwrap(), Some(b"value".to_vec()));
+        assert_eq!(kv.get(&key).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_kv_wal_compression_changed_between_opens() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.compression = WALCompression::ZstdDict {
+            level: 3,
+            dictionary: Arc::new(b"value".repeat(100)),
+        };
+        opts.wal_options.compression_threshold = 0;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        let key = random_bytes32().to_vec();
+        kv.put(key.clone(), b"value".repeat(10)).unwrap();
+        drop(kv);
+
+        let mut opts = KVOptions::default();
+        opts.wal_options.compression = WALCompression::None;
+        let kv = KV::new(dir.path(), opts).unwrap();
+        assert_eq!(kv.get(&key).unwrap(), Some(b"value".repeat(10)));
+        let other_key = random_bytes32().to_vec();
+        kv.put(other_key.clone(), b"raw".to_vec()).unwrap();
+        drop(kv);
+
+        let kv = KV::open_read_only(dir.path()).unwrap();
+        assert_eq!(kv.get(&key).unwrap(), Some(b"value".repeat(10)));
+        assert_eq!(kv.get(&other_key).unwrap(), Some(b"raw".to_vec()));
+    }
+
+    #[test]
+    fn test_kv_batch_with_invalid_key_applies_nothing() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        let key = random_bytes32().to_vec();
+        let mut batch = Batch::new();
+        batch
+            .put(key.clone(), b"value".to_vec())
+            .put(b"short".to_vec(), b"oops".to_vec());
+        assert!(matches!(kv.batch(batch), Err(KVError::InvalidKeyLength)));
+        assert_eq!(kv.get(&key).unwrap(), None);
+
+        let mut batch = Batch::new();
+        batch.put(key.clone(), b"value".to_vec());
+        batch.clear();
+        assert!(batch.is_empty());
+        kv.batch(batch).unwrap();
+        assert_eq!(kv.get(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn test_kv_custom_key_store_options() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.key_store_options.key_size = 16;
+        opts.key_store_options.bucket_count = 4;
+        opts.key_store_options.init_entry_num_for_each_bucket = 64;
+        opts.wal_options.flush_size = 1; // Rotate and flush on every write
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        let key = vec![7u8; 16];
+        kv.put(key.clone(), b"value".to_vec()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.buckets_index.bucket_count(), 4);
+        drop(kv);
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        assert_eq!(kv.get(&key).unwrap(), Some(b"value".to_vec()));
+        drop(kv);
+
+        let result = KV::new(dir.path(), KVOptions::default());
+        assert!(matches!(
+            result,
+            Err(KVError::OptionsMismatch {
+                option: "key_size",
+                ..
+            })
+        ));
+
+        let mut mismatched = opts.clone();
+        mismatched.key_store_options.bucket_count = 8;
+        let result = KV::new(dir.path(), mismatched);
+        assert!(matches!(
+            result,
+            Err(KVError::OptionsMismatch {
+                option: "bucket_count",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_kv_variable_length_keys() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.key_store_options.max_key_size = 1024;
+        opts.key_store_options.inline_value_size = 8;
+
+        // Keys of up to 27 bytes fit in the 32 byte key field, longer ones are fingerprinted
+        let mut keys: Vec<Vec<u8>> = vec![Vec::new(), b"a".to_vec(), vec![b'k'; 27]];
+        keys.extend([28, 100, 1024].map(|len| (0..len).map(|i| i as u8).collect::<Vec<u8>>()));
+        let values: Vec<Vec<u8>> = (0..keys.len()).map(|i| vec![i as u8; i * 3]).collect();
+        let expected: BTreeMap<Vec<u8>, Vec<u8>> =
+            keys.iter().cloned().zip(values.iter().cloned()).collect();
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        for (key, value) in &expected {
+            kv.put(key.clone(), value.clone()).unwrap();
+        }
+        assert!(matches!(
+            kv.put(vec![0u8; 1025], b"value".to_vec()),
+            Err(KVError::InvalidKeyLength)
+        ));
+        drop(kv);
+
+        // Replayed from the WAL, then read back from the stores
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        for (key, value) in &expected {
+            assert_eq!(kv.get(key).unwrap().as_ref(), Some(value));
+        }
+        kv.flush().unwrap();
+        drop(kv);
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        for (key, value) in &expected {
+            assert_eq!(kv.get(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(kv.get(&[0u8; 28]).unwrap(), None);
+        let stored: BTreeMap<_, _> = kv.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(stored, expected);
+        let stored_keys: Vec<Vec<u8>> = kv.keys().map(|key| key.unwrap()).collect();
+        assert_eq!(stored_keys.len(), keys.len());
+
+        kv.delete(keys[4].clone()).unwrap();
+        kv.put(keys[5].clone(), b"new".to_vec()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.get(&keys[4]).unwrap(), None);
+        assert_eq!(kv.get(&keys[5]).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(kv.flush_context().reclaim_pages().unwrap(), (0, 0));
+        drop(kv);
+
+        let result = KV::new(dir.path(), KVOptions {
+            key_store_options: BucketsOptions {
+                max_key_size: 0,
+                ..opts.key_store_options.clone()
+            },
+            ..opts.clone()
+        });
+        assert!(matches!(
+            result,
+            Err(KVError::OptionsMismatch {
+                option: "max_key_size",
+                ..
+            })
+        ));
+
+        let other_dir = tempdir().unwrap();
+        let mut small_key_size = opts;
+        small_key_size.key_store_options.key_size = 16;
+        assert!(matches!(
+            KV::new(other_dir.path(), small_key_size),
+            Err(KVError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_kv_pre_hashed_keys() {
+        use sha2::{Digest, Sha256};
+
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.key_store_options.pre_hashed = true;
+        opts.key_store_options.init_entry_num_for_each_bucket = 64;
+
+        let keys: Vec<Vec<u8>> = (0..2000u32)
+            .map(|i| Sha256::digest(i.to_le_bytes()).to_vec())
+            .collect();
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        for key in &keys {
+            kv.put(key.clone(), key[..4].to_vec()).unwrap();
+        }
+        kv.flush().unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        assert!(kv.buckets_index.pre_hashed());
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(key[..4].to_vec()));
+        }
+        drop(kv);
+
+        assert!(matches!(
+            KV::new(dir.path(), KVOptions::default()),
+            Err(KVError::OptionsMismatch {
+                option: "pre_hashed",
+                ..
+            })
+        ));
+        opts.key_store_options.max_key_size = 64;
+        assert!(matches!(
+            KV::new(tempdir().unwrap().path(), opts.clone()),
+            Err(KVError::InvalidOptions(_))
+        ));
+        opts.key_store_options.max_key_size = 0;
+        opts.key_store_options.key_size = 7;
+        assert!(matches!(
+            KV::new(tempdir().unwrap().path(), opts),
+            Err(KVError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_kv_inline_values() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.key_store_options.inline_value_size = 8;
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        let keys: Vec<Vec<u8>> = (0..4).map(|_| random_bytes32().to_vec()).collect();
+        let values = [b"flag".to_vec(), Vec::new(), b"8 bytes!".to_vec(), vec![1u8; 100]];
+        for (key, value) in keys.iter().zip(&values) {
+            kv.put(key.clone(), value.clone()).unwrap();
+        }
+        kv.flush().unwrap();
+        assert!(matches!(
+            kv.buckets_index.get(&keys[2]).unwrap(),
+            Some(DataInfo::Inline(value)) if value == values[2]
+        ));
+        assert!(matches!(
+            kv.buckets_index.get(&keys[3]).unwrap(),
+            Some(DataInfo::Paged { .. })
+        ));
+        drop(kv);
+
+        let kv = KV::new(dir.path(), opts.clone()).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(kv.get(key).unwrap().as_ref(), Some(value));
+        }
+        let stored: BTreeMap<_, _> = kv.iter().map(|item| item.unwrap()).collect();
+        assert_eq!(stored, keys.iter().cloned().zip(values.clone()).collect());
+
+        // Moving a value between the entry and the value store keeps the pages in step
+        kv.put(keys[0].clone(), vec![2u8; 100]).unwrap();
+        kv.put(keys[3].clone(), b"small".to_vec()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.get(&keys[3]).unwrap(), Some(b"small".to_vec()));
+        assert_eq!(kv.flush_context().reclaim_pages().unwrap(), (0, 0));
+        let (freed, _) = kv.level_page_bitmap.rebuild_allocation(&HashSet::new()).unwrap();
+        assert_eq!(freed, 1);
+        drop(kv);
+
+        let result = KV::new(dir.path(), KVOptions {
+            key_store_options: BucketsOptions::default(),
+            ..opts
+        });
+        assert!(matches!(
+            result,
+            Err(KVError::OptionsMismatch {
+                option: "inline_value_size",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_kv_overwrite_frees_old_page() {
+        let dir = tempdir().unwrap();
+        let mut opts = KVOptions::default();
+        opts.wal_options.flush_size = 1; // Rotate and flush on every write
+        let kv = KV::new(dir.path(), opts).unwrap();
+
+        let key = random_bytes32().to_vec();
+        for i in 0..20u8 {
+            kv.put(key.clone(), vec![i; 16]).unwrap();
+            kv.flush().unwrap();
+        }
+
+        // Each flush allocates the new page before freeing the old one,
+        // so an overwritten key keeps bouncing between the first two pages
+        let data_info = kv.buckets_index.get(&key).unwrap().unwrap();
+        assert!(matches!(
+            data_info,
+            DataInfo::Paged { data_id, .. } if data_id & 0x00FFFFFFFFFFFFFF < 2
+        ));
+        assert_eq!(kv.get(&key).unwrap(), Some(vec![19u8; 16]));
+    }
+
+    #[test]
+    fn test_kv_values_larger_than_largest_page() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..3).map(|_| random_bytes32().to_vec()).collect();
+        let values: Vec<Vec<u8>> = [5 * 1024, 4096 * 3, 100_000]
+            .iter()
+            .map(|&len| (0..len).map(|i| (i % 251) as u8).collect())
+            .collect();
+        for (key, value) in keys.iter().zip(&values) {
+            kv.put(key.clone(), value.clone()).unwrap();
+        }
+        kv.flush().unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(kv.get(key).unwrap().as_ref(), Some(value));
+        }
+        // Chained pages are referenced and freed with their value
+        assert_eq!(kv.flush_context().reclaim_pages().unwrap(), (0, 0));
+        kv.put(keys[0].clone(), b"small".to_vec()).unwrap();
+        kv.delete(keys[2].clone()).unwrap();
+        kv.flush().unwrap();
+        let allocated: usize = kv
+            .buckets_index
+            .get(&keys[1])
+            .unwrap()
+            .unwrap()
+            .pages(&kv.level_page_bitmap)
+            .unwrap()
+            .len();
+        assert_eq!(allocated, 4);
+        // Only the chain of keys[1] and the small value are left
+        let (freed, _) = kv.level_page_bitmap.rebuild_allocation(&HashSet::new()).unwrap();
+        assert_eq!(freed, allocated + 1);
+
+        assert!(matches!(
+            kv.put(random_bytes32().to_vec(), vec![0u8; MAX_VALUE_SIZE + 1]),
+            Err(KVError::ValueTooLarge)
+        ));
+
+        // Zeroed values are not touched before the batch is rejected
+        let mut batch = Batch::new();
+        for _ in 0..3 {
+            batch.put(random_bytes32().to_vec(), vec![0u8; MAX_VALUE_SIZE]);
+        }
+        assert!(matches!(kv.batch(batch), Err(KVError::BatchTooLarge)));
+    }
+
+    #[test]
+    fn test_kv_interrupted_flush_reflushed_without_leak() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..10).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), key.clone()).unwrap();
+        }
+
+        // Rotate by hand, without starting the flush thread
+        let pre_wal_id = kv.rotate_wal(&mut kv.current_wal.write().unwrap()).unwrap();
+        let meta = Meta::load_from_file(dir.path().join(KV_META_FILE_NAME)).unwrap();
+        assert_eq!(meta.current_wal_id, pre_wal_id + 1);
+
+        // Crash in the middle of the flush: half of the keys written to the stores
+        // and one page allocated but never referenced
+        kv.flush_context().save_checkpoint(Some(pre_wal_id)).unwrap();
+        for key in &keys[..5] {
+            let data_id = kv.level_page_bitmap.write(key.clone()).unwrap();
+            let data_info = DataInfo::Paged {
+                data_id,
+                data_len: key.len() as u32,
+            };
+            kv.buckets_index.put(key.clone(), data_info).unwrap();
+        }
+        kv.level_page_bitmap.write(b"leaked".to_vec()).unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        kv.flush().unwrap();
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(key.clone()));
+        }
+        assert_eq!(kv.meta.read().unwrap().flushing_wal_id, None);
+        assert!(!kv.wal_file_path(pre_wal_id).exists());
+        // Every allocated page is referenced by a key
+        assert_eq!(kv.flush_context().reclaim_pages().unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_kv_interrupted_flush_restores_lost_page_bits() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..10).map(|_| random_bytes32().to_vec()).collect();
+        for key in &keys {
+            kv.put(key.clone(), vec![key[0]; 100]).unwrap();
+        }
+        let pre_wal_id = kv.rotate_wal(&mut kv.current_wal.write().unwrap()).unwrap();
+
+        // Crash in the middle of the flush, losing the allocation bit of a referenced page
+        kv.flush_context().save_checkpoint(Some(pre_wal_id)).unwrap();
+        let mut data_ids = Vec::new();
+        for key in &keys[..5] {
+            let data_id = kv.level_page_bitmap.write(vec![key[0]; 100]).unwrap();
+            let data_info = DataInfo::Paged {
+                data_id,
+                data_len: 100,
+            };
+            kv.buckets_index.put(key.clone(), data_info).unwrap();
+            data_ids.push(data_id);
+        }
+        kv.level_page_bitmap.free(data_ids[0]).unwrap();
+        drop(kv);
+
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        kv.flush().unwrap();
+        assert_eq!(kv.flush_context().reclaim_pages().unwrap(), (0, 0));
+
+        // New values must not land on the pages of the recovered ones
+        let others: Vec<Vec<u8>> = (0..10).map(|_| random_bytes32().to_vec()).collect();
+        for key in &others {
+            kv.put(key.clone(), vec![!key[0]; 100]).unwrap();
+        }
+        kv.flush().unwrap();
+        // Read from the stores rather than the cache
+        drop(kv);
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        for key in &keys {
+            assert_eq!(kv.get(key).unwrap(), Some(vec![key[0]; 100]));
+        }
+        for key in &others {
+            assert_eq!(kv.get(key).unwrap(), Some(vec![!key[0]; 100]));
+        }
+    }
+
+    #[test]
+    fn test_kv_parallel_recovery_keeps_write_order() {
+        let dir = tempdir().unwrap();
+        let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
+        let key = random_bytes32().to_vec();
+        let mut keys = Vec::new();
+        // Leave one WAL per write behind, each overwriting the same key
+        for i in 0..6u8 {
+            kv.put(key.clone(), vec![i]).unwrap();
+            keys.push(random_bytes32().to_vec());
+            kv.put(keys[i as usize].clone(), vec![i]).unwrap();
+            kv.rotate_wal(&mut kv.current_wal.write().unwrap()).unwrap();
+        }
+        kv.put(key.clone(), b"current".to_vec()).unwrap();
+        let last_seq = kv.last_sequence();
+        drop(kv);
+
+        let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut opts = KVOptions::default();
+        opts.wal_options.recovery_threads = 3;
+        let reported = progress.clone();
+        opts.wal_options.recovery_progress = Some(Arc::new(move |progress| {
+            reported.lock().unwrap().push(progress);
+        }));
+        let kv = KV::new(dir.path(), opts).unwrap();
+        assert_eq!(kv.get(&key).unwrap(), Some(b"current".to_vec()));
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(kv.get(key).unwrap(), Some(vec![i as u8]));
+        }
+        assert_eq!(kv.last_sequence(), last_seq);
+        let flushing_ids: Vec<u64> = kv
+            .flushing_buffers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|buffer| buffer.wal_id)
+            .collect();
+        assert!(flushing_ids.is_sorted());
+
+        let mut progress = progress.lock().unwrap().clone();
+        progress.sort_by_key(|progress| progress.replayed);
+        assert_eq!(progress.len(), 7);
+        assert!(progress.iter().all(|progress| progress.total == 7));
+        let mut wal_ids: Vec<u64> = progress.iter().map(|progress| progress.wal_id).collect();
+        wal_ids.sort_unstable();
+        assert_eq!(wal_ids, (0..7).collect::<Vec<u64>>());
+
+        kv.flush().unwrap();
+        assert_eq!(kv.get(&key).unwrap(), Some(b"current".to_vec()));
+    }
+
     #[test]
     fn test_kv_invalid_key_length() {
         let dir = tempdir().unwrap();
@@ -583,7 +2411,7 @@ mod tests {
         let value = vec![7u8; 64]; // Each small value of 64 bytes
 
         // Write 100_000 entries
-        for i in 0..100_00 {
+        for i in 0..10_000 {
             let key = format!("key_{:012}", i).into_bytes();
             let mut fixed_key = key.clone();
             fixed_key.resize(32, 0);
@@ -591,16 +2419,14 @@ mod tests {
             keys.push(fixed_key);
         }
 
-        // Trigger flush
-        //kv.trigger_async_flush();
-        thread::sleep(Duration::from_secs(20));
+        kv.flush().unwrap();
 
         drop(kv);
 
         let kv = KV::new(dir.path(), KVOptions::default()).unwrap();
 
         // Random sample verification
-        for i in (0..100_00).step_by(10_00) {
+        for i in (0..10_000).step_by(1_000) {
             let key = &keys[i];
             let result = kv.get(key).unwrap();
             assert!(result.is_some(), "key: {}", i);
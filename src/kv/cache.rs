@@ -0,0 +1,130 @@
+use quick_cache::Weighter;
+use quick_cache::sync::Cache;
+
+const DEFAULT_CACHE_CAPACITY: u64 = 64 * 1024 * 1024;
+
+// Rough average entry size, only used to pre-size the cache
+const ESTIMATED_ENTRY_SIZE: u64 = 1024;
+
+#[derive(Clone)]
+pub struct CacheOptions {
+    /// Maximum total bytes of cached keys and values, 0 disables the cache
+    pub capacity: u64,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            capacity: DEFAULT_CACHE_CAPACITY,
+        }
+    }
+}
+
+/// Weighs an entry by the bytes of its key and value
+#[derive(Clone)]
+struct SizeWeighter;
+
+impl Weighter<Vec<u8>, Vec<u8>> for SizeWeighter {
+    fn weight(&self, key: &Vec<u8>, value: &Vec<u8>) -> u64 {
+        (key.len() + value.len()) as u64
+    }
+}
+
+/// Size-bounded read cache for values stored in the persistent layer
+pub(crate) struct KVCache {
+    cache: Option<Cache<Vec<u8>, Vec<u8>, SizeWeighter>>,
+}
+
+impl KVCache {
+    pub fn new(opts: &CacheOptions) -> Self {
+        if opts.capacity == 0 {
+            return Self { cache: None };
+        }
+        let estimated_items = (opts.capacity / ESTIMATED_ENTRY_SIZE).max(1) as usize;
+        Self {
+            cache: Some(Cache::with_weighter(
+                estimated_items,
+                opts.capacity,
+                SizeWeighter,
+            )),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.cache.as_ref()?.get(key)
+    }
+
+    pub fn insert(&self, key: Vec<u8>, value: Vec<u8>) {
+        if let Some(cache) = &self.cache {
+            cache.insert(key, value);
+        }
+    }
+
+    /// Replace the value of a cached key, a key not cached stays uncached
+    pub fn update(&self, key: Vec<u8>, value: Vec<u8>) {
+        if let Some(cache) = &self.cache
+            && let Err((key, _)) = cache.replace(key, value, true)
+        {
+            // Not cached, or too large to be kept: the old value must not stay behind
+            cache.remove(&key);
+        }
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        if let Some(cache) = &self.cache {
+            cache.remove(key);
+        }
+    }
+
+    /// Total bytes currently held by the cache
+    #[cfg(test)]
+    pub fn weight(&self) -> u64 {
+        self.cache.as_ref().map_or(0, |cache| cache.weight())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_insert_get_remove() {
+        let cache = KVCache::new(&CacheOptions::default());
+
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        assert_eq!(cache.get(b"key"), Some(b"value".to_vec()));
+
+        cache.insert(b"key".to_vec(), b"new value".to_vec());
+        assert_eq!(cache.get(b"key"), Some(b"new value".to_vec()));
+
+        cache.remove(b"key");
+        assert_eq!(cache.get(b"key"), None);
+
+        // Only keys already cached are updated
+        cache.update(b"key".to_vec(), b"updated".to_vec());
+        assert_eq!(cache.get(b"key"), None);
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        cache.update(b"key".to_vec(), b"updated".to_vec());
+        assert_eq!(cache.get(b"key"), Some(b"updated".to_vec()));
+    }
+
+    #[test]
+    fn test_cache_bounded_by_capacity() {
+        let capacity = 64 * 1024;
+        let cache = KVCache::new(&CacheOptions { capacity });
+
+        for i in 0..10_000u32 {
+            cache.insert(i.to_le_bytes().to_vec(), vec![0u8; 100]);
+        }
+        assert!(cache.weight() <= capacity);
+    }
+
+    #[test]
+    fn test_cache_disabled() {
+        let cache = KVCache::new(&CacheOptions { capacity: 0 });
+
+        cache.insert(b"key".to_vec(), b"value".to_vec());
+        assert_eq!(cache.get(b"key"), None);
+        assert_eq!(cache.weight(), 0);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::kv::{Batch, KVError};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// Lets concurrent writers share one WAL append and fsync.
+///
+/// A writer that finds no group being written becomes the leader: it takes every queued
+/// batch and writes them as one group, then wakes up the writers of that group.
+/// Writers arriving in the meantime queue up for the next group.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    queue: Mutex<CommitQueue>,
+    committed: Condvar,
+}
+
+#[derive(Default)]
+struct CommitQueue {
+    next_ticket: u64,
+    pending: Vec<(u64, Batch)>,
+    // Whether a leader is writing a group
+    writing: bool,
+    // Results of committed batches not picked up by their writer yet
+    results: HashMap<u64, Result<(), KVError>>,
+}
+
+impl GroupCommit {
+    /// Queue `batch` and block until it is committed.
+    ///
+    /// `write` is called by the leader with the batches of a group in queue order,
+    /// its result is returned to every writer of the group. If it panics the other
+    /// writers of the group get an error and the next group can be written.
+    pub fn commit(
+        &self,
+        batch: Batch,
+        write: impl FnOnce(Vec<Batch>) -> Result<(), KVError>,
+    ) -> Result<(), KVError> {
+        let mut queue = self.queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, batch));
+
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if !queue.writing {
+                // Not committed and no group in flight, so the batch is still queued
+                break;
+            }
+            queue = self.committed.wait(queue).unwrap();
+        }
+
+        queue.writing = true;
+        let (tickets, batches): (Vec<u64>, Vec<Batch>) =
+            std::mem::take(&mut queue.pending).into_iter().unzip();
+        drop(queue);
+
+        let mut group = GroupGuard {
+            group_commit: self,
+            leader: ticket,
+            tickets,
+            result: None,
+        };
+        let result = write(batches);
+        group.result = Some(result.as_ref().map_err(follower_error).copied());
+        drop(group);
+        result
+    }
+}
+
+/// Hands the result of a group to its followers when the leader is done writing it,
+/// or unwinds from a panic while writing
+struct GroupGuard<'a> {
+    group_commit: &'a GroupCommit,
+    leader: u64,
+    tickets: Vec<u64>,
+    // None if writing the group panicked
+    result: Option<Result<(), KVError>>,
+}
+
+impl Drop for GroupGuard<'_> {
+    fn drop(&mut self) {
+        let result = self
+            .result
+            .take()
+            .unwrap_or_else(|| Err(KVError::Other("Writing the group panicked".to_string())));
+        let mut queue = self
+            .group_commit
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        queue.writing = false;
+        for &follower in self.tickets.iter().filter(|t| **t != self.leader) {
+            let follower_result = result.as_ref().map_err(follower_error).copied();
+            queue.results.insert(follower, follower_result);
+        }
+        self.group_commit.committed.notify_all();
+    }
+}
+
+/// Copy of the error of a failed group for every writer besides the leader
+fn follower_error(err: &KVError) -> KVError {
+    match err {
+        KVError::Io(e) => KVError::Io(io::Error::new(e.kind(), e.to_string())),
+        e => KVError::Other(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_group_commit_batches_writers() {
+        let group_commit = Arc::new(GroupCommit::default());
+        // Size of every group, and whether it failed
+        let groups = Arc::new(Mutex::new(Vec::new()));
+        let errors = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let group_commit = group_commit.clone();
+                let groups = groups.clone();
+                let errors = errors.clone();
+                thread::spawn(move || {
+                    for j in 0..50u8 {
+                        let mut batch = Batch::new();
+                        batch.put(vec![i, j], Vec::new());
+                        let result = group_commit.commit(batch, |batches| {
+                            let fail = batches.iter().any(|b| b.ops[0].0 == [0, 25]);
+                            groups.lock().unwrap().push((batches.len(), fail));
+                            if fail {
+                                return Err(KVError::Io(io::Error::other("failed")));
+                            }
+                            Ok(())
+                        });
+                        if let Err(e) = result {
+                            assert!(matches!(e, KVError::Io(_)));
+                            errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let groups = groups.lock().unwrap();
+        assert_eq!(groups.iter().map(|(len, _)| len).sum::<usize>(), 8 * 50);
+        let failed: Vec<usize> = groups
+            .iter()
+            .filter(|(_, fail)| *fail)
+            .map(|(len, _)| *len)
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(errors.load(Ordering::Relaxed), failed[0]);
+        assert!(group_commit.queue.lock().unwrap().results.is_empty());
+    }
+
+    #[test]
+    fn test_group_commit_leader_panic_releases_followers() {
+        let group_commit = Arc::new(GroupCommit::default());
+        // Hold a group in flight while two writers queue up for the next one
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let blocker = {
+            let group_commit = group_commit.clone();
+            thread::spawn(move || {
+                group_commit.commit(Batch::new(), |_| {
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok(())
+                })
+            })
+        };
+        started_rx.recv().unwrap();
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let group_commit = group_commit.clone();
+                thread::spawn(move || group_commit.commit(Batch::new(), |_| panic!("write failed")))
+            })
+            .collect();
+        while group_commit.queue.lock().unwrap().pending.len() < 2 {
+            thread::yield_now();
+        }
+        release_tx.send(()).unwrap();
+        blocker.join().unwrap().unwrap();
+
+        // The leader panicked, the other writer of its group got an error
+        let results: Vec<_> = writers.into_iter().map(|writer| writer.join()).collect();
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert!(results.iter().any(|result| matches!(result, Ok(Err(KVError::Other(_))))));
+        assert!(group_commit.commit(Batch::new(), |_| Ok(())).is_ok());
+        assert!(group_commit.queue.lock().unwrap().results.is_empty());
+    }
+}
@@ -1,10 +1,11 @@
 use crate::kv::data::level_page_bitmap::page_bitmap::PageBitmap;
-use moka::sync::Cache;
+use log::error;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs::{File, create_dir_all};
 use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 mod page_bitmap;
 
@@ -23,8 +24,6 @@ struct Meta {
 pub(crate) struct LevelPage {
     levels: Vec<PageBitmap>,
     levels_page_size: Vec<u32>,
-    base_dir: PathBuf,
-    meta: Meta,
 }
 
 #[derive(Clone)]
@@ -71,9 +70,9 @@ impl LevelPage {
                     level_count,
                 } => {
                     let mut level_page_sizes = Vec::with_capacity(level_count as usize);
-                    level_page_sizes.push(start_page_size as u32);
+                    level_page_sizes.push(start_page_size);
                     for i in 1..=level_count - 1 {
-                        level_page_sizes.push((level_page_sizes[i as usize - 1] * 2));
+                        level_page_sizes.push(level_page_sizes[i as usize - 1] * 2);
                     }
                     level_page_sizes
                 }
@@ -94,6 +93,18 @@ impl LevelPage {
             meta
         };
 
+        Self::open_levels(&base_dir, &meta, false)
+    }
+
+    /// Open an existing value store without write access
+    pub fn open_read_only(base_dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let base_dir = base_dir.into();
+        let file = File::open(base_dir.join("meta.json"))?;
+        let meta: Meta = serde_json::from_reader(BufReader::new(file))?;
+        Self::open_levels(&base_dir, &meta, true)
+    }
+
+    fn open_levels(base_dir: &Path, meta: &Meta, read_only: bool) -> std::io::Result<Self> {
         // recover PageBitmap
         let mut levels = Vec::new();
         let mut levels_page_size = Vec::new();
@@ -108,7 +119,11 @@ impl LevelPage {
                 file_meta.page_size, file_meta.file_index
             ));
 
-            let page_bitmap = PageBitmap::new(&index_path, &data_path, file_meta.page_size, None)?;
+            let page_bitmap = if read_only {
+                PageBitmap::open_read_only(&index_path, &data_path, file_meta.page_size)?
+            } else {
+                PageBitmap::new(&index_path, &data_path, file_meta.page_size)?
+            };
             levels.push(page_bitmap);
 
             if !levels_page_size.contains(&file_meta.page_size) {
@@ -119,13 +134,60 @@ impl LevelPage {
         Ok(Self {
             levels,
             levels_page_size,
-            base_dir,
-            meta,
         })
     }
 
-    /// Write data into the most suitable PageBitmap
+    fn max_page_size(&self) -> usize {
+        *self.levels_page_size.last().unwrap() as usize
+    }
+
+    /// Write a value and return the data_id of its first page.
+    ///
+    /// A value larger than the largest page is chained: every page but the last is
+    /// a largest page holding `[part][next data_id u64]`, the last one holds the rest.
+    /// Its length tells readers how to follow the chain.
     pub fn write(&self, value: Vec<u8>) -> std::io::Result<u64> {
+        if value.len() <= self.max_page_size() {
+            return self.write_page(value);
+        }
+        let part_size = self.max_page_size().saturating_sub(CHAIN_LINK_LEN);
+        if part_size == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Largest page is too small to chain values",
+            ));
+        }
+
+        let parts = chain_parts(value.len(), part_size, self.max_page_size());
+        // Written from the last part backwards, each page links to the one written before
+        let mut written: Vec<u64> = Vec::with_capacity(parts.len());
+        for part in parts.iter().rev() {
+            let mut page = value[part.clone()].to_vec();
+            if let Some(&next) = written.last() {
+                page.extend_from_slice(&next.to_le_bytes());
+            }
+            match self.write_page(page) {
+                Ok(data_id) => written.push(data_id),
+                Err(e) => {
+                    // Nothing refers to the pages written so far. One that cannot be freed
+                    // leaks until `rebuild_allocation` runs after an interrupted flush.
+                    for data_id in written {
+                        if let Err(free_err) = self.free(data_id) {
+                            error!(
+                                "Failed to free page {} of a partially written value: {:?}",
+                                data_id, free_err
+                            );
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(*written.last().unwrap())
+    }
+
+    /// Write data into the most suitable PageBitmap
+    fn write_page(&self, value: Vec<u8>) -> std::io::Result<u64> {
         let size = value.len() as u32;
         assert!(size <= *self.levels_page_size.last().unwrap());
 
@@ -146,6 +208,75 @@ impl LevelPage {
         Ok(encoded)
     }
 
+    /// Free every page of a value of `len` bytes
+    pub fn free_value(&self, data_id: u64, len: u32) -> std::io::Result<()> {
+        for data_id in self.value_pages(data_id, len)? {
+            self.free(data_id)?;
+        }
+        Ok(())
+    }
+
+    /// Read a value of `len` bytes, following its chain if it spans several pages
+    pub fn read_value(&self, data_id: u64, len: u32) -> std::io::Result<Vec<u8>> {
+        let len = len as usize;
+        if len <= self.max_page_size() {
+            let mut value = self.read(data_id)?;
+            value.truncate(len);
+            return Ok(value);
+        }
+        let mut value = Vec::with_capacity(len);
+        for (data_id, part) in self.value_pages(data_id, len as u32)?.into_iter().zip(
+            chain_parts(len, self.max_page_size() - CHAIN_LINK_LEN, self.max_page_size()),
+        ) {
+            let page = self.read(data_id)?;
+            value.extend_from_slice(&page[..part.len()]);
+        }
+        Ok(value)
+    }
+
+    /// data_ids of every page of a value of `len` bytes, the first one being `data_id`.
+    ///
+    /// A link to a page that does not exist or is too small for its part fails with
+    /// `InvalidData`, the chain is corrupt.
+    pub fn value_pages(&self, data_id: u64, len: u32) -> std::io::Result<Vec<u64>> {
+        let len = len as usize;
+        if len <= self.max_page_size() {
+            return Ok(vec![data_id]);
+        }
+        let part_size = self.max_page_size() - CHAIN_LINK_LEN;
+        let parts = chain_parts(len, part_size, self.max_page_size());
+        let mut pages = vec![data_id];
+        // The last page has no link
+        for part in &parts[..parts.len() - 1] {
+            let data_id = *pages.last().unwrap();
+            self.check_chained(data_id, part.len() + CHAIN_LINK_LEN)?;
+            let page = self.read(data_id)?;
+            pages.push(read_link(&page, part.len())?);
+        }
+        self.check_chained(*pages.last().unwrap(), parts.last().unwrap().len())?;
+        Ok(pages)
+    }
+
+    /// Check that `data_id` is a page of the value store holding at least `len` bytes
+    fn check_chained(&self, data_id: u64, len: usize) -> std::io::Result<()> {
+        let level_idx = (data_id >> 56) as usize;
+        let page_idx = data_id & 0x00FFFFFFFFFFFFFF;
+        let valid = self
+            .levels
+            .get(level_idx)
+            .zip(self.levels_page_size.get(level_idx))
+            .is_some_and(|(level, &page_size)| {
+                page_idx < level.page_count() && page_size as usize >= len
+            });
+        if !valid {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Corrupt chained page {:#x}", data_id),
+            ));
+        }
+        Ok(())
+    }
+
     pub fn free(&self, data_id: u64) -> std::io::Result<()> {
         let level_idx = (data_id >> 56) as usize;
         let page_idx = data_id & 0x00FFFFFFFFFFFFFF;
@@ -153,6 +284,50 @@ impl LevelPage {
         Ok(())
     }
 
+    /// Flush all levels to disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        for level in &self.levels {
+            level.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Make the allocated pages exactly the data_ids in `referenced`: pages not in it are
+    /// freed, pages in it whose bit was lost are marked allocated again.
+    /// Returns the number of freed and of marked pages.
+    pub fn rebuild_allocation(
+        &self,
+        referenced: &HashSet<u64>,
+    ) -> std::io::Result<(usize, usize)> {
+        let mut allocated = HashSet::new();
+        let mut freed = 0;
+        for (level_idx, level) in self.levels.iter().enumerate() {
+            for page_idx in level.allocated_pages() {
+                let data_id = ((level_idx as u64) << 56) | page_idx;
+                if referenced.contains(&data_id) {
+                    allocated.insert(data_id);
+                } else {
+                    level.free_page(page_idx)?;
+                    freed += 1;
+                }
+            }
+        }
+
+        let mut marked = 0;
+        for &data_id in referenced.difference(&allocated) {
+            let level_idx = (data_id >> 56) as usize;
+            let level = self.levels.get(level_idx).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Invalid level index: {}", level_idx),
+                )
+            })?;
+            level.mark_page(data_id & 0x00FFFFFFFFFFFFFF)?;
+            marked += 1;
+        }
+        Ok((freed, marked))
+    }
+
     /// Read data
     pub fn read(&self, data_id: u64) -> std::io::Result<Vec<u8>> {
         let level = (data_id >> 56) as usize;
@@ -169,6 +344,30 @@ impl LevelPage {
     }
 }
 
+// Bytes of the data_id linking a chained page to the next one
+const CHAIN_LINK_LEN: usize = 8;
+
+/// Byte ranges of the pages of a chained value: parts of `part_size` bytes while more
+/// than `max_page_size` bytes are left, then the rest
+fn chain_parts(len: usize, part_size: usize, max_page_size: usize) -> Vec<Range<usize>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while len - start > max_page_size {
+        parts.push(start..start + part_size);
+        start += part_size;
+    }
+    parts.push(start..len);
+    parts
+}
+
+fn read_link(page: &[u8], offset: usize) -> std::io::Result<u64> {
+    page.get(offset..offset + CHAIN_LINK_LEN)
+        .map(|link| u64::from_le_bytes(link.try_into().unwrap()))
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Truncated chained page")
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -219,22 +418,69 @@ mod tests {
         let read_large_re = lpb_recovered.read(id_large).unwrap();
 
         assert_eq!(
-            &read_small[..data_small.len()],
+            &read_small_re[..data_small.len()],
             &data_small[..],
             "small data content mismatch"
         );
         assert_eq!(
-            &read_medium[..data_medium.len()],
+            &read_medium_re[..data_medium.len()],
             &data_medium[..],
             "medium data content mismatch"
         );
         assert_eq!(
-            &read_large[..data_large.len()],
+            &read_large_re[..data_large.len()],
             &data_large[..],
             "large data content mismatch"
         );
     }
 
+    #[test]
+    fn test_level_page_bitmap_chained_values() {
+        let dir = TempDir::new().unwrap();
+        let lpb = LevelPage::new(dir.path(), LevelPageOptions::default()).unwrap();
+
+        // Around the boundaries where the chain gets one more page
+        for len in [4097, 4088 + 4096, 4088 + 4097, 3 * 4088 + 1] {
+            let value: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+            let data_id = lpb.write(value.clone()).unwrap();
+            assert_eq!(lpb.read_value(data_id, len as u32).unwrap(), value);
+
+            let pages = lpb.value_pages(data_id, len as u32).unwrap();
+            assert_eq!(pages.len(), chain_parts(len, 4088, 4096).len());
+            lpb.free_value(data_id, len as u32).unwrap();
+            assert_eq!(lpb.rebuild_allocation(&HashSet::new()).unwrap(), (0, 0));
+        }
+    }
+
+    #[test]
+    fn test_level_page_bitmap_corrupt_chain() {
+        let dir = TempDir::new().unwrap();
+        let lpb = LevelPage::new(dir.path(), LevelPageOptions::default()).unwrap();
+        let len = 3 * 4088 + 1;
+        let value = vec![7u8; len];
+        let data_id = lpb.write(value.clone()).unwrap();
+        let pages = lpb.value_pages(data_id, len as u32).unwrap();
+        let page_count = lpb.levels[lpb.levels.len() - 1].page_count();
+        let data_file = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().contains("data_4096b_"))
+            .unwrap();
+        let data_file = std::fs::OpenOptions::new().write(true).open(data_file).unwrap();
+
+        // Links to a missing level, past the last page, and to a page too small for its part
+        let small_page = lpb.write(vec![1u8; 10]).unwrap();
+        for link in [u64::MAX, pages[1] + page_count, small_page] {
+            let mut page = value[..4088].to_vec();
+            page.extend_from_slice(&link.to_le_bytes());
+            let offset = (pages[0] & 0x00FFFFFFFFFFFFFF) * 4096;
+            std::os::unix::fs::FileExt::write_at(&data_file, &page, offset).unwrap();
+            let err = lpb.value_pages(data_id, len as u32).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(lpb.read_value(data_id, len as u32).unwrap_err().kind(), err.kind());
+        }
+    }
+
     #[test]
     fn test_level_page_bitmap_large_volume() {
         use super::*;
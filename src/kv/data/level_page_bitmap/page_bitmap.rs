@@ -1,10 +1,8 @@
 use bitvec::prelude::*;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, Write};
 use std::os::unix::fs::FileExt;
 use std::path::Path;
-use std::sync::{Arc, Mutex, RwLock};
-use moka::sync::Cache;
+use std::sync::{Mutex, RwLock};
 
 #[derive(Debug)]
 pub struct PageBitmap {
@@ -22,31 +20,33 @@ impl PageBitmap {
         index_file_path: &Path,
         data_file_path: &Path,
         page_size: u32,
-        cache: Option<Arc<Cache<u64, Vec<u8>>>>,
     ) -> std::io::Result<Self> {
         // Open or create index file
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(index_file_path)?;
         // check file size
         if index_file_path.metadata()?.len() == 0 {
-            let mut data_file = OpenOptions::new()
+            let data_file = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(data_file_path)?;
 
             let data_size = 4096 * page_size;
             data_file.write_at(&[0], (data_size - 1) as u64)?;
             data_file.sync_all()?;
 
-            let mut levels: Vec<BitVec<u8, Lsb0>> = Vec::new();
-            levels.push(bitvec![u8, Lsb0; 0; 8 * 8 * 8 * 8]);
-            levels.push(bitvec![u8, Lsb0; 0; 8 * 8 * 8]);
-            levels.push(bitvec![u8, Lsb0; 0; 8 * 8]);
-            levels.push(bitvec![u8, Lsb0; 0; 8]);
+            let levels: Vec<BitVec<u8, Lsb0>> = vec![
+                bitvec![u8, Lsb0; 0; 8 * 8 * 8 * 8],
+                bitvec![u8, Lsb0; 0; 8 * 8 * 8],
+                bitvec![u8, Lsb0; 0; 8 * 8],
+                bitvec![u8, Lsb0; 0; 8],
+            ];
 
             // Initialize index file size = 4096 * page_size bits
             let total_size = 4096u64;
@@ -63,14 +63,26 @@ impl PageBitmap {
             })
         } else {
             // Recover from existing files
-            let page_bitmap = Self::recover_from_file(index_file_path, data_file_path, page_size)?;
+            let page_bitmap =
+                Self::recover_from_file(index_file_path, data_file_path, page_size, true)?;
             Ok(page_bitmap)
         }
     }
+
+    /// Open existing files without write access, allocating or freeing pages fails
+    pub(crate) fn open_read_only(
+        index_file_path: &Path,
+        data_file_path: &Path,
+        page_size: u32,
+    ) -> std::io::Result<Self> {
+        Self::recover_from_file(index_file_path, data_file_path, page_size, false)
+    }
+
     fn recover_from_file(
         index_file_path: &Path,
         data_file_path: &Path,
         page_size: u32,
+        writable: bool,
     ) -> std::io::Result<Self> {
         let index_meta = std::fs::metadata(index_file_path)?;
         let data_meta = std::fs::metadata(data_file_path)?;
@@ -94,7 +106,7 @@ impl PageBitmap {
 
         let index_file = OpenOptions::new()
             .read(true)
-            .write(true)
+            .write(writable)
             .open(index_file_path)?;
         let mut buffer = vec![0u8; index_meta.len() as usize];
         index_file.read_at(&mut buffer, 0)?;
@@ -116,7 +128,7 @@ impl PageBitmap {
 
         let data_file = OpenOptions::new()
             .read(true)
-            .write(true)
+            .write(writable)
             .open(data_file_path)?;
         Ok(Self {
             meta_lock: Mutex::new(()),
@@ -192,7 +204,7 @@ impl PageBitmap {
     /// Expand PageBitmap if needed
     fn expand_if_need(&self) -> std::io::Result<()> {
         let mut levels_write = self.levels.write().unwrap();
-        let mut top_level_idx = levels_write.len() - 1;
+        let top_level_idx = levels_write.len() - 1;
         let top_level = &levels_write[top_level_idx];
 
         // Check if expansion is needed
@@ -218,7 +230,7 @@ impl PageBitmap {
                 expand_and_zero(
                     &self.data_file,
                     before_len as u64 * self.page_size as u64,
-                    (after_len as u64 * self.page_size as u64),
+                    after_len as u64 * self.page_size as u64,
                 )?;
             }
         }
@@ -306,6 +318,54 @@ impl PageBitmap {
         Ok(())
     }
 
+    /// Mark a page as used, e.g. one still referenced whose bit was lost in a crash
+    pub fn mark_page(&self, idx: u64) -> std::io::Result<()> {
+        let idx_usize = idx as usize;
+        let mut levels = self.levels.write().unwrap();
+        if idx_usize >= levels[0].len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Page {} is out of range", idx),
+            ));
+        }
+
+        self.set_file_bit(idx_usize, true)?;
+        levels[0].set(idx_usize, true);
+
+        // Update parent levels
+        let mut child_idx = idx_usize;
+        for lvl in 0..levels.len() - 1 {
+            let parent_idx = child_idx / 8;
+            let child_range = parent_idx * 8..(parent_idx + 1) * 8;
+
+            if levels[lvl][child_range.clone()].all() {
+                levels[lvl + 1].set(parent_idx, true);
+            } else {
+                break;
+            }
+            child_idx = parent_idx;
+        }
+
+        Ok(())
+    }
+
+    /// Number of pages the files have room for, used or not
+    pub fn page_count(&self) -> u64 {
+        self.levels.read().unwrap()[0].len() as u64
+    }
+
+    /// Indexes of all pages currently in use
+    pub fn allocated_pages(&self) -> Vec<u64> {
+        let levels = self.levels.read().unwrap();
+        levels[0].iter_ones().map(|idx| idx as u64).collect()
+    }
+
+    /// Flush index and data file to disk
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.data_file.sync_all()?;
+        self.index_file.sync_all()
+    }
+
     /// Set or clear a bit in index file
     fn set_file_bit(&self, page_idx: usize, value: bool) -> std::io::Result<()> {
         let byte_index = page_idx / 8;
@@ -351,7 +411,7 @@ mod tests {
         let data_file = dir.path().join("data.dat");
 
         let page_size = 128u32;
-        let bitmap = PageBitmap::new(&index_file, &data_file, page_size, None).unwrap();
+        let bitmap = PageBitmap::new(&index_file, &data_file, page_size).unwrap();
 
         // Write one page
         let data = vec![1u8; page_size as usize];
@@ -376,7 +436,7 @@ mod tests {
         let data_file = dir.path().join("data.dat");
 
         let page_size = 64u32;
-        let bitmap = PageBitmap::new(&index_file, &data_file, page_size,None).unwrap();
+        let bitmap = PageBitmap::new(&index_file, &data_file, page_size).unwrap();
 
         // Writing oversized page should fail
         let data = vec![1u8; (page_size + 1) as usize];
@@ -393,7 +453,7 @@ mod tests {
         let page_size = 128u32;
 
         {
-            let bitmap = PageBitmap::new(&index_file, &data_file, page_size, None).unwrap();
+            let bitmap = PageBitmap::new(&index_file, &data_file, page_size).unwrap();
             let data = vec![42u8; page_size as usize];
             let page_idx = bitmap.write_page(data.clone()).unwrap();
 
@@ -402,7 +462,8 @@ mod tests {
         }
 
         // Reload and ensure state can be recovered
-        let recovered = PageBitmap::recover_from_file(&index_file, &data_file, page_size).unwrap();
+        let recovered =
+            PageBitmap::recover_from_file(&index_file, &data_file, page_size, true).unwrap();
         let data = vec![43u8; page_size as usize];
         let page_idx = recovered.write_page(data.clone()).unwrap();
         let read_back = recovered.read_page(page_idx).unwrap();
@@ -416,7 +477,7 @@ mod tests {
         let data_file = dir.path().join("data.dat");
 
         let page_size = 64u32;
-        let bitmap = PageBitmap::new(&index_file, &data_file, page_size, None).unwrap();
+        let bitmap = PageBitmap::new(&index_file, &data_file, page_size).unwrap();
 
         // Allocate multiple pages continuously
         let mut pages = vec![];
@@ -438,7 +499,7 @@ mod tests {
         }
 
         // Write 5 more pages, should reuse freed ones
-        for i in 0..5 {
+        for _ in 0..5 {
             let data = vec![99u8; page_size as usize];
             let page_idx = bitmap.write_page(data.clone()).unwrap();
             let read_back = bitmap.read_page(page_idx).unwrap();
@@ -453,7 +514,7 @@ mod tests {
         let data_path = dir.path().join("data.dat");
 
         // Page size = 16 bytes
-        let bitmap = PageBitmap::new(&index_path, &data_path, 16, None).unwrap();
+        let bitmap = PageBitmap::new(&index_path, &data_path, 16).unwrap();
 
         let mut allocated = Vec::new();
 
@@ -498,7 +559,7 @@ mod tests {
         }
 
         // Reallocate 500 pages to check reuse correctness
-        for (i, (old_id, _)) in allocated.iter().take(500).enumerate() {
+        for (i, _) in allocated.iter().take(500).enumerate() {
             let data = vec![0xAB; 16];
             let new_page_id = bitmap.write_page(data.clone()).unwrap();
             let read_back = bitmap.read_page(new_page_id).unwrap();
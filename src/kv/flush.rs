@@ -0,0 +1,340 @@
+use crate::kv::cache::KVCache;
+use crate::kv::data::level_page_bitmap::LevelPage;
+use crate::kv::index::buckets::Buckets;
+use crate::kv::key_layout::KeyLayout;
+use crate::kv::meta::Meta;
+use crate::kv::utils::{remove_file_if_exists, sync_dir};
+use crate::kv::{DataInfo, FlushingBuffer, KVError, KVOp};
+use log::error;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle, sleep};
+use std::time::Duration;
+
+#[derive(Default)]
+struct FlushStatus {
+    // Whether a flush thread is alive
+    running: bool,
+    // Set on close, no flush thread is started afterwards
+    closing: bool,
+    handle: Option<JoinHandle<()>>,
+    // Id of the last WAL whose buffer has been flushed, WALs are flushed in id order
+    flushed_wal_id: Option<u64>,
+    // Bumped whenever a buffer is flushed or the thread stops
+    progress: u64,
+}
+
+/// Progress of the flush thread, shared with flush handles.
+///
+/// Lock order is `status`, then `flushing_buffers`: `stop_unless` looks at the buffers with
+/// `status` held. No path may lock `status` while holding `flushing_buffers`, which is why
+/// `wait_flushed_while` checks `blocked` without `status` held.
+#[derive(Default)]
+pub(crate) struct FlushState {
+    status: Mutex<FlushStatus>,
+    flushed: Condvar,
+}
+
+impl FlushState {
+    /// Start a flush thread unless one is running or the KV is closing
+    pub fn start(&self, flush_context: impl FnOnce() -> FlushContext) {
+        let mut status = self.status.lock().unwrap();
+        if status.running || status.closing {
+            return;
+        }
+        if let Some(handle) = status.handle.take() {
+            // The previous thread has stopped, only reap it
+            let _ = handle.join();
+        }
+        let flush_context = flush_context();
+        status.running = true;
+        status.handle = Some(thread::spawn(move || flush_context.run()));
+    }
+
+    /// Stop starting flush threads and wait for the running one to finish its current buffer
+    pub fn close(&self) -> thread::Result<()> {
+        let handle = {
+            let mut status = self.status.lock().unwrap();
+            status.closing = true;
+            status.handle.take()
+        };
+        match handle {
+            Some(handle) => handle.join(),
+            None => Ok(()),
+        }
+    }
+
+    /// Mark the thread stopped if the KV is closing or `has_work` is false, returns whether
+    /// it stopped. `has_work` runs with `status` held, so a buffer pushed concurrently is
+    /// either seen by it or finds the thread stopped and starts a new one.
+    fn stop_unless(&self, has_work: impl FnOnce() -> bool) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if !status.closing && has_work() {
+            return false;
+        }
+        status.running = false;
+        status.progress += 1;
+        // Wake up waiters that will never see their WAL flushed after close
+        self.flushed.notify_all();
+        true
+    }
+
+    fn mark_flushed(&self, wal_id: u64) {
+        let mut status = self.status.lock().unwrap();
+        status.flushed_wal_id = Some(wal_id);
+        status.progress += 1;
+        self.flushed.notify_all();
+    }
+
+    fn is_flushed(&self, wal_id: u64) -> bool {
+        self.status.lock().unwrap().flushed_wal_id >= Some(wal_id)
+    }
+
+    /// Block while `blocked` holds, it is checked again whenever a buffer has been flushed.
+    ///
+    /// `blocked` runs without `status` held, it may take locks the flush thread holds
+    /// while updating the status.
+    pub fn wait_flushed_while(&self, mut blocked: impl FnMut() -> bool) {
+        loop {
+            // Read before checking, so a flush in between is not waited for
+            let progress = self.status.lock().unwrap().progress;
+            if !blocked() {
+                return;
+            }
+            let status = self.status.lock().unwrap();
+            let status = self
+                .flushed
+                .wait_while(status, |status| {
+                    status.progress == progress && (status.running || !status.closing)
+                })
+                .unwrap();
+            // Nothing is flushed anymore once closed and the thread has stopped
+            if !status.running && status.closing {
+                return;
+            }
+        }
+    }
+
+    fn wait_flushed(&self, wal_id: u64) {
+        let status = self.status.lock().unwrap();
+        let _status = self
+            .flushed
+            .wait_while(status, |status| {
+                // Nothing is flushed anymore once closed and the thread has stopped
+                status.flushed_wal_id < Some(wal_id) && (status.running || !status.closing)
+            })
+            .unwrap();
+    }
+}
+
+/// Handle returned by `KV::flush_async` to wait for the flush to complete
+pub struct FlushHandle {
+    state: Arc<FlushState>,
+    // Last WAL that has to be flushed, None if nothing was pending
+    wal_id: Option<u64>,
+}
+
+impl FlushHandle {
+    pub(crate) fn new(state: Arc<FlushState>, wal_id: Option<u64>) -> Self {
+        Self { state, wal_id }
+    }
+
+    /// Whether all data written before the flush was started is persisted
+    pub fn is_done(&self) -> bool {
+        self.wal_id.is_none_or(|wal_id| self.state.is_flushed(wal_id))
+    }
+
+    /// Block until all data written before the flush was started is persisted,
+    /// or until the KV is closed
+    pub fn wait(self) {
+        if let Some(wal_id) = self.wal_id {
+            self.state.wait_flushed(wal_id);
+        }
+    }
+}
+
+/// State shared with the background flush thread
+pub(crate) struct FlushContext {
+    pub state: Arc<FlushState>,
+    pub meta: Arc<RwLock<Meta>>,
+    pub meta_path: PathBuf,
+    pub wal_dir: PathBuf,
+    pub flushing_buffers: Arc<RwLock<Vec<Arc<FlushingBuffer>>>>,
+    pub level_page_bitmap: Arc<LevelPage>,
+    pub buckets_index: Arc<Buckets<DataInfo>>,
+    pub key_layout: KeyLayout,
+    pub cache: Arc<KVCache>,
+}
+
+/// Retry `f` until it succeeds, the flush thread has no caller to report errors to
+fn retry<T, E: Debug>(what: &str, delay: Duration, mut f: impl FnMut() -> Result<T, E>) -> T {
+    loop {
+        match f() {
+            Ok(v) => return v,
+            Err(e) => {
+                error!("Failed to {}, retrying: {:?}", what, e);
+                sleep(delay);
+            }
+        }
+    }
+}
+
+impl FlushContext {
+    /// Flush buffers oldest first until none are left
+    pub fn run(&self) {
+        loop {
+            // `status` is locked before the buffers here, so the buffers must never be held
+            // while locking `status`, see `FlushState`. Buffers left on close are still in
+            // their WAL and flushed after reopening.
+            let has_work = || !self.flushing_buffers.read().unwrap().is_empty();
+            if self.state.stop_unless(has_work) {
+                return;
+            }
+            // Only this thread removes buffers, the one seen above is still there
+            let flushing_buffer = self.flushing_buffers.read().unwrap()[0].clone();
+            // Flushed without the lock, so rotations and reads are not held up. Reads still
+            // find the buffer until it is removed below, after the stores hold its values.
+            self.flush_buffer(&flushing_buffer);
+            // The write lock is released before `mark_flushed` locks `status`
+            self.flushing_buffers.write().unwrap().remove(0);
+            self.state.mark_flushed(flushing_buffer.wal_id);
+        }
+    }
+
+    /// Flush one buffer with a checkpoint protocol:
+    ///
+    /// 1. record the WAL id as `flushing_wal_id` in kv.meta
+    /// 2. write the new values to the value store and sync it
+    /// 3. apply every operation to the key store, freeing the replaced pages
+    /// 4. sync the value store, then the key store
+    /// 5. remove the WAL file and sync the WAL directory
+    /// 6. clear `flushing_wal_id`
+    ///
+    /// After a crash before step 5 the WAL is replayed and flushed again. Re-applying
+    /// an operation is idempotent since a put frees the page it replaces, and pages
+    /// allocated but never referenced are freed by `reclaim_pages` on load.
+    /// No entry can refer to a chained value before its links are on disk, otherwise
+    /// the pages followed by the replay and by `reclaim_pages` could be garbage.
+    fn flush_buffer(&self, flushing_buffer: &FlushingBuffer) {
+        let short = Duration::from_secs(1);
+        let long = Duration::from_secs(5);
+
+        retry("save flush checkpoint", short, || {
+            self.save_checkpoint(Some(flushing_buffer.wal_id))
+        });
+
+        let mut puts = Vec::new();
+        for (key, op) in &flushing_buffer.buffer {
+            if let KVOp::Put { value } = op {
+                puts.push((key, value, self.write_value(key, value)));
+            }
+        }
+        if puts.iter().any(|(_, _, data_info)| matches!(data_info, DataInfo::Paged { .. })) {
+            retry("sync value store", short, || self.level_page_bitmap.sync());
+        }
+
+        for (key, value, data_info) in puts {
+            let stored_key = self.key_layout.encode(key);
+            let replaced = retry("put into buckets_index", short, || {
+                self.buckets_index.put(stored_key.clone(), data_info.clone())
+            });
+            // Release the pages of the overwritten value
+            if let Some(replaced) = replaced {
+                retry("free data_id", short, || self.free_replaced(&replaced, &data_info));
+            }
+            // Must happen before the buffer is dropped so reads never see a stale value.
+            // Only keys already cached are updated, a bulk load keeps the hot set.
+            self.cache.update(key.clone(), value.clone());
+        }
+        for (key, op) in &flushing_buffer.buffer {
+            if let KVOp::Del {} = op {
+                let stored_key = self.key_layout.encode(key);
+                let deleted = retry("del key from buckets_index", long, || {
+                    self.buckets_index.del(&stored_key)
+                });
+                self.cache.remove(key);
+                if let Some(data_info) = deleted {
+                    retry("free data_id", short, || data_info.free(&self.level_page_bitmap));
+                }
+            }
+        }
+
+        retry("sync value store", short, || self.level_page_bitmap.sync());
+        retry("sync key store", short, || self.buckets_index.sync());
+        retry("remove WAL file", long, || {
+            remove_file_if_exists(&flushing_buffer.wal_path)?;
+            sync_dir(&self.wal_dir)
+        });
+        retry("clear flush checkpoint", short, || self.save_checkpoint(None));
+    }
+
+    /// Store a value for `key`, in the value store unless it fits in the bucket entry
+    fn write_value(&self, key: &[u8], value: &[u8]) -> DataInfo {
+        let long_key = self.key_layout.is_long(key);
+        // Small values live in the bucket entry, no page is allocated for them.
+        // A long key is stored in front of its value, so the entry has no room left.
+        let inline_value_size = self.buckets_index.inline_value_size() as usize;
+        if !long_key && value.len() <= inline_value_size {
+            return DataInfo::Inline(value.to_vec());
+        }
+        let data = if long_key {
+            [key, value].concat()
+        } else {
+            value.to_vec()
+        };
+        let data_len = data.len() as u32;
+        let data_id = retry("write level_page_bitmap", Duration::from_secs(1), || {
+            self.level_page_bitmap.write(data.clone())
+        });
+        DataInfo::Paged { data_id, data_len }
+    }
+
+    /// Free the pages of a value overwritten by `data_info`. The pages of both can only
+    /// overlap if the allocation lost track of the old ones, those now hold the new value
+    /// and are kept.
+    fn free_replaced(&self, replaced: &DataInfo, data_info: &DataInfo) -> io::Result<()> {
+        let new_pages = data_info.pages(&self.level_page_bitmap)?;
+        let DataInfo::Paged { data_id, .. } = replaced else {
+            return Ok(());
+        };
+        // A reused first page holds the new value, its chain links cannot be followed
+        let pages = if new_pages.contains(data_id) {
+            vec![*data_id]
+        } else {
+            replaced.pages(&self.level_page_bitmap)?
+        };
+        for page in pages {
+            if new_pages.contains(&page) {
+                error!("Page {} of an overwritten value holds the new value, not freeing it", page);
+            } else {
+                self.level_page_bitmap.free(page)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_checkpoint(&self, flushing_wal_id: Option<u64>) -> io::Result<()> {
+        let mut meta = self.meta.write().unwrap();
+        meta.flushing_wal_id = flushing_wal_id;
+        meta.save_to_file(&self.meta_path)
+    }
+
+    /// Rebuild the page allocation from the bucket entries after an interrupted flush:
+    /// pages no entry refers to were allocated before the key store was updated and are
+    /// freed, referenced pages whose bit was lost in a crash are marked allocated again
+    /// so the flush replayed next cannot reuse them.
+    /// Returns the number of freed and of marked pages.
+    pub fn reclaim_pages(&self) -> Result<(usize, usize), KVError> {
+        let mut referenced = HashSet::new();
+        for idx in 0..self.buckets_index.bucket_count() {
+            for (_, data_info) in self.buckets_index.bucket_entries(idx as usize)? {
+                // Every page of a chained value is referenced through the first one
+                referenced.extend(data_info.pages(&self.level_page_bitmap)?);
+            }
+        }
+        Ok(self.level_page_bitmap.rebuild_allocation(&referenced)?)
+    }
+}
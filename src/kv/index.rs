@@ -1,2 +1,3 @@
 pub mod bucket;
-pub mod buckets;
\ No newline at end of file
+pub mod buckets;
+pub mod hash;
\ No newline at end of file
@@ -1,13 +1,16 @@
-use crate::kv::utils::{create_file_with_len, remove_file_if_exists};
-use dashmap::DashMap;
+use crate::kv::index::hash::KeyHash;
+use crate::kv::utils::{create_file_with_len, remove_file_if_exists, sync_dir};
 use std::fs::{File, OpenOptions, rename};
-use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{self, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 
 pub trait BucketValue: Sized {
+    /// Bytes reserved for the value in an entry, the encoding must not exceed it
+    /// unless the buckets reserve room for inline values
+    const ENCODED_SIZE: u32 = size_of::<Self>() as u32;
+
     fn encode(&self) -> Vec<u8>;
     fn decode(bytes: &[u8]) -> Option<Self>;
 }
@@ -48,15 +51,6 @@ pub enum EntryMeta {
     Occupied = 1,
 }
 
-impl EntryMeta {
-    pub fn new_free() -> Self {
-        EntryMeta::Free
-    }
-    pub fn new_occupied() -> Self {
-        EntryMeta::Occupied
-    }
-}
-
 /// A single entry
 pub struct Entry<T: BucketValue> {
     meta: EntryMeta,
@@ -104,17 +98,6 @@ impl<T: BucketValue> Entry<T> {
     pub fn set_free(&mut self) {
         self.meta = EntryMeta::Free;
     }
-
-    pub fn set_occupied(&mut self) {
-        self.meta = EntryMeta::Occupied;
-    }
-}
-
-/// Data info
-#[derive(Clone, Debug)]
-pub struct DataInfo {
-    pub data_id: u64,
-    pub data_len: u32,
 }
 
 struct InnerData {
@@ -128,22 +111,15 @@ pub struct Bucket<T: BucketValue> {
     dir: PathBuf,
     key_size: u32,
     entry_size: u32,
+    hash: KeyHash,
     _marker: std::marker::PhantomData<T>,
 }
 
 const MAX_SEARCH_DEFAULT: usize = 32;
 
-#[derive(Debug)]
-pub enum RehashError {
-    Io(io::Error),
-    MaxSearchExceeded,
-}
+// Entries read at once by `entries`, so a large bucket is not read into memory whole
+const ENTRIES_READ_CHUNK: u64 = 1024;
 
-impl From<io::Error> for RehashError {
-    fn from(e: io::Error) -> Self {
-        RehashError::Io(e)
-    }
-}
 const DEFAULT_FILE_NAME: &str = "bucket.dat";
 impl<T: BucketValue> Bucket<T> {
     pub fn new<P: AsRef<Path>>(
@@ -151,6 +127,7 @@ impl<T: BucketValue> Bucket<T> {
         key_size: u32,
         value_size: u32,
         init_entry_num: u32,
+        hash: KeyHash,
     ) -> Result<Self, BucketError> {
         let dir = dir.as_ref();
         std::fs::create_dir_all(dir)?; // Ensure directory exists
@@ -161,6 +138,7 @@ impl<T: BucketValue> Bucket<T> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)?;
 
         let entry_size = Entry::<T>::entry_size(key_size, value_size as usize);
@@ -174,145 +152,121 @@ impl<T: BucketValue> Bucket<T> {
             file_len = file.metadata()?.len();
         }
 
+        Ok(Self::from_file(dir, file, file_len, key_size, entry_size, hash))
+    }
+
+    /// Open an existing bucket without write access, mutations fail
+    pub fn open_read_only<P: AsRef<Path>>(
+        dir: P,
+        key_size: u32,
+        value_size: u32,
+        hash: KeyHash,
+    ) -> Result<Self, BucketError> {
+        let dir = dir.as_ref();
+        let file = File::open(dir.join(DEFAULT_FILE_NAME))?;
+        let entry_size = Entry::<T>::entry_size(key_size, value_size as usize);
+        let file_len = file.metadata()?.len();
+        Ok(Self::from_file(dir, file, file_len, key_size, entry_size, hash))
+    }
+
+    fn from_file(
+        dir: &Path,
+        file: File,
+        file_len: u64,
+        key_size: u32,
+        entry_size: u32,
+        hash: KeyHash,
+    ) -> Self {
         let inner_data = RwLock::new(InnerData {
             file,
             entry_num: file_len / entry_size as u64,
         });
 
-        Ok(Self {
+        Self {
             inner_data,
             dir: dir.to_path_buf(),
             key_size,
             entry_size,
+            hash,
             _marker: std::marker::PhantomData,
-        })
+        }
     }
 
     fn get_max_search(&self) -> usize {
         MAX_SEARCH_DEFAULT
     }
 
-    /// Read multiple entries at once
-    fn read_entries(&self, start_index: u64, count: usize) -> Result<Vec<Entry<T>>, BucketError> {
-        let mut entries = Vec::with_capacity(count);
-        let mut buf = vec![0u8; self.entry_size as usize * count];
+    /// Read the probe window of a key: up to max_search entries starting at its hashed
+    /// slot, wrapping around the end of the file. Returns each entry with its index.
+    fn read_probe_window(
+        &self,
+        inner: &InnerData,
+        key: &[u8],
+    ) -> Result<Vec<(u64, Entry<T>)>, BucketError> {
+        let entry_num = inner.entry_num;
+        let start_index = self.hash.hash(key) % entry_num;
+        let window = (self.get_max_search() as u64).min(entry_num);
 
-        for i in 0..count {
-            let index = (start_index + i as u64) % self.inner_data.read().unwrap().entry_num;
-            let offset = index as u64 * self.entry_size as u64;
-            self.inner_data.read().unwrap().file.read_at(
-                &mut buf[i * self.entry_size as usize..(i + 1) * self.entry_size as usize],
-                offset,
-            )?;
-        }
+        let entry_size = self.entry_size as usize;
+        let mut buf = vec![0u8; window as usize * entry_size];
+        let entries_until_end = window.min(entry_num - start_index) as usize;
+        let (first, second) = buf.split_at_mut(entries_until_end * entry_size);
+        inner
+            .file
+            .read_exact_at(first, start_index * self.entry_size as u64)?;
+        inner.file.read_exact_at(second, 0)?;
+
+        buf.chunks_exact(entry_size)
+            .enumerate()
+            .map(|(i, entry_buf)| {
+                let index = (start_index + i as u64) % entry_num;
+                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize)
+                    .ok_or_else(|| BucketError::Other(format!("Corrupt entry at {}", index)))?;
+                Ok((index, entry))
+            })
+            .collect()
+    }
 
-        for i in 0..count {
-            let entry_bytes =
-                &buf[i * self.entry_size as usize..(i + 1) * self.entry_size as usize];
-            let entry = Entry::<T>::decode(entry_bytes, self.key_size as usize).unwrap();
-            entries.push(entry);
-        }
-        Ok(entries)
+    fn write_entry(&self, inner: &InnerData, index: u64, entry: &Entry<T>) -> io::Result<()> {
+        let encoded = entry.encode(self.key_size as usize);
+        inner
+            .file
+            .write_all_at(&encoded, index * self.entry_size as u64)
     }
 
-    pub fn put(&self, key: Vec<u8>, value: T) -> Result<(), BucketError> {
+    /// Insert or update a key, returns the replaced value if the key existed.
+    ///
+    /// The whole probe window is searched for the key before a free slot is used,
+    /// so a slot freed by a delete never leads to a second copy of the key.
+    pub fn put(&self, key: Vec<u8>, value: T) -> Result<Option<T>, BucketError> {
         if key.len() != self.key_size as usize {
             return Err(BucketError::InvalidKeyLength);
         }
-        
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
 
         let inner = self.inner_data.read().unwrap();
-        let entry_num = inner.entry_num;
-        let start_index = hash % entry_num;
-        let max_search = self.get_max_search();
-
-        let entry_size = self.entry_size as usize;
-        let start_offset = start_index * self.entry_size as u64;
-        let entries_until_end = (entry_num - start_index) as usize;
-        
-        if max_search <= entries_until_end {
-            let mut buf = vec![0u8; max_search as usize * entry_size];
-            inner.file.read_at(&mut buf, start_offset)?;
-
-            for i in 0..max_search as usize {
-                let offset_in_buf = i * entry_size;
-                let entry_buf = &buf[offset_in_buf..offset_in_buf + entry_size];
-                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize).unwrap();
-
-                if entry.is_free() || entry.key == key {
-                    let index = (start_index + i as u64) % entry_num;
-                    let file_offset = index * self.entry_size as u64;
-
-                    let new_entry = Entry {
-                        meta: EntryMeta::Occupied,
-                        key,
-                        value,
-                    };
-                    let encoded = new_entry.encode(self.key_size as usize);
-                    inner.file.write_all_at(&encoded, file_offset)?;
-                    return Ok(());
-                }
+        let mut free_index = None;
+        let mut existing = None;
+        for (index, entry) in self.read_probe_window(&inner, &key)? {
+            if entry.is_occupied() && entry.key == key {
+                existing = Some((index, entry.value));
+                break;
             }
-
-            return Err(BucketError::MaxSearchReached);
-        }
-        
-        let first_entries = entries_until_end;
-        let second_entries = max_search as usize - first_entries;
-        
-        if first_entries > 0 {
-            let mut buf1 = vec![0u8; first_entries * entry_size];
-            inner.file.read_at(&mut buf1, start_offset)?;
-
-            for i in 0..first_entries {
-                let offset_in_buf = i * entry_size;
-                let entry_buf = &buf1[offset_in_buf..offset_in_buf + entry_size];
-                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize).unwrap();
-
-                if entry.is_free() || entry.key == key {
-                    let index = (start_index + i as u64) % entry_num;
-                    let file_offset = index * self.entry_size as u64;
-
-                    let new_entry = Entry {
-                        meta: EntryMeta::Occupied,
-                        key,
-                        value,
-                    };
-                    let encoded = new_entry.encode(self.key_size as usize);
-                    inner.file.write_all_at(&encoded, file_offset)?;
-                    return Ok(());
-                }
-            }
-        }
-        if second_entries > 0 {
-            let mut buf2 = vec![0u8; second_entries * entry_size];
-            inner.file.read_at(&mut buf2, 0)?;
-
-            for i in 0..second_entries {
-                let offset_in_buf = i * entry_size;
-                let entry_buf = &buf2[offset_in_buf..offset_in_buf + entry_size];
-                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize).unwrap();
-
-                if entry.is_free() || entry.key == key {
-                    let index = i as u64; // 因为从头开始
-                    let file_offset = index * self.entry_size as u64;
-
-                    let new_entry = Entry {
-                        meta: EntryMeta::Occupied,
-                        key,
-                        value,
-                    };
-                    let encoded = new_entry.encode(self.key_size as usize);
-                    inner.file.write_all_at(&encoded, file_offset)?;
-                    return Ok(());
-                }
+            if entry.is_free() && free_index.is_none() {
+                free_index = Some(index);
             }
         }
 
-        Err(BucketError::MaxSearchReached)
+        let (index, replaced) = match existing {
+            Some((index, replaced)) => (index, Some(replaced)),
+            None => (free_index.ok_or(BucketError::MaxSearchReached)?, None),
+        };
+        let new_entry = Entry {
+            meta: EntryMeta::Occupied,
+            key,
+            value,
+        };
+        self.write_entry(&inner, index, &new_entry)?;
+        Ok(replaced)
     }
 
     pub fn get(&self, key: &[u8]) -> Result<Option<T>, BucketError> {
@@ -320,99 +274,63 @@ impl<T: BucketValue> Bucket<T> {
             return Err(BucketError::InvalidKeyLength);
         }
 
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-
         let inner = self.inner_data.read().unwrap();
-        let entry_num = inner.entry_num;
-        let start_index = hash % entry_num;
-        let max_search = self.get_max_search();
-
-        let entry_size = self.entry_size as usize;
-        let start_offset = start_index * self.entry_size as u64;
-        let entries_until_end = (entry_num - start_index) as usize;
-
-        if max_search <= entries_until_end {
-            let mut buf = vec![0u8; max_search as usize * entry_size];
-            inner.file.read_at(&mut buf, start_offset)?;
-
-            for i in 0..max_search as usize {
-                let offset = i * entry_size;
-                let entry_buf = &buf[offset..offset + entry_size];
-                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize).unwrap();
-
-                if entry.is_occupied() && entry.key == key {
-                    return Ok(Some(entry.value));
-                }
-            }
-            return Ok(None);
-        }
-
-        let first_entries = entries_until_end;
-        let second_entries = max_search as usize - first_entries;
-
-        if first_entries > 0 {
-            let mut buf1 = vec![0u8; first_entries * entry_size];
-            inner.file.read_at(&mut buf1, start_offset)?;
-
-            for i in 0..first_entries {
-                let offset = i * entry_size;
-                let entry_buf = &buf1[offset..offset + entry_size];
-                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize).unwrap();
-
-                if entry.is_occupied() && entry.key == key {
-                    return Ok(Some(entry.value));
-                }
-            }
-        }
-
-        if second_entries > 0 {
-            let mut buf2 = vec![0u8; second_entries * entry_size];
-            inner.file.read_at(&mut buf2, 0)?;
-
-            for i in 0..second_entries {
-                let offset = i * entry_size;
-                let entry_buf = &buf2[offset..offset + entry_size];
-                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize).unwrap();
-
-                if entry.is_occupied() && entry.key == key {
-                    return Ok(Some(entry.value));
-                }
-            }
-        }
-
-        Ok(None)
+        Ok(self
+            .read_probe_window(&inner, key)?
+            .into_iter()
+            .find(|(_, entry)| entry.is_occupied() && entry.key == key)
+            .map(|(_, entry)| entry.value))
     }
 
+    /// Delete a key, returns its value if it existed
     pub fn del(&self, key: &[u8]) -> Result<Option<T>, BucketError> {
         if key.len() != self.key_size as usize {
             return Err(BucketError::InvalidKeyLength);
         }
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
-        let inner_data_with_read_lock = self.inner_data.read().unwrap();
-        let start_index = hash % inner_data_with_read_lock.entry_num as u64;
-        let max_search = self.get_max_search();
-
-        for i in 0..max_search {
-            let index = (start_index + i as u64) % inner_data_with_read_lock.entry_num;
-            let offset = index * self.entry_size as u64;
-
-            let mut buf = vec![0u8; self.entry_size as usize];
-            inner_data_with_read_lock.file.read_at(&mut buf, offset)?;
-            let mut entry = Entry::<T>::decode(&buf, self.key_size as usize).unwrap();
 
+        let inner = self.inner_data.read().unwrap();
+        let mut deleted = None;
+        // Free every copy, files written before upserts searched the whole window may hold duplicates
+        for (index, mut entry) in self.read_probe_window(&inner, key)? {
             if entry.is_occupied() && entry.key == key {
                 entry.set_free();
-                let encoded = entry.encode(self.key_size as usize);
-                inner_data_with_read_lock.file.write_at(&encoded, offset)?;
-                return Ok(Some(entry.value));
+                self.write_entry(&inner, index, &entry)?;
+                if deleted.is_none() {
+                    deleted = Some(entry.value);
+                }
             }
         }
+        Ok(deleted)
+    }
 
-        Ok(None)
+    /// Flush the bucket file to disk
+    pub fn sync(&self) -> Result<(), BucketError> {
+        self.inner_data.read().unwrap().file.sync_all()?;
+        Ok(())
+    }
+
+    /// Read all occupied entries, `ENTRIES_READ_CHUNK` entries at a time
+    pub fn entries(&self) -> Result<Vec<(Vec<u8>, T)>, BucketError> {
+        let inner = self.inner_data.read().unwrap();
+        let entry_size = self.entry_size as usize;
+        let mut buf = vec![0u8; ENTRIES_READ_CHUNK as usize * entry_size];
+
+        let mut entries = Vec::new();
+        let mut index = 0;
+        while index < inner.entry_num {
+            let count = ENTRIES_READ_CHUNK.min(inner.entry_num - index);
+            let chunk = &mut buf[..count as usize * entry_size];
+            inner.file.read_exact_at(chunk, index * self.entry_size as u64)?;
+            for entry_buf in chunk.chunks_exact(entry_size) {
+                let entry = Entry::<T>::decode(entry_buf, self.key_size as usize)
+                    .ok_or_else(|| BucketError::Other(format!("Corrupt entry at {}", index)))?;
+                if entry.is_occupied() {
+                    entries.push((entry.key, entry.value));
+                }
+                index += 1;
+            }
+        }
+        Ok(entries)
     }
 
     pub fn expand(&self) -> Result<(), BucketError> {
@@ -456,20 +374,24 @@ impl<T: BucketValue> Bucket<T> {
                 inner_data_with_read_lock
                     .file
                     .read_at(&mut buf, offset)?;
-                let entry = Entry::<T>::decode(&buf, key_size).unwrap();
+                let entry = Entry::<T>::decode(&buf, key_size)
+                    .ok_or_else(|| BucketError::Other(format!("Corrupt entry at {}", i)))?;
 
                 if entry.is_occupied() {
-                    let mut hasher = DefaultHasher::new();
-                    entry.key.hash(&mut hasher);
-                    let hash = hasher.finish();
-                    let mut new_index = (hash % new_entry_num) as usize;
+                    let mut new_index = (self.hash.hash(&entry.key) % new_entry_num) as usize;
 
                     let mut searched = 0;
                     while searched < MAX_SEARCH_DEFAULT {
                         let new_offset = new_index as u64 * self.entry_size as u64;
                         let mut new_buf = vec![0u8; entry_size];
                         new_file.read_at(&mut new_buf, new_offset)?;
-                        let new_entry = Entry::<T>::decode(&new_buf, key_size).unwrap();
+                        let new_entry =
+                            Entry::<T>::decode(&new_buf, key_size).ok_or_else(|| {
+                                BucketError::Other(format!(
+                                    "Corrupt entry at {} of the expanded bucket",
+                                    new_index
+                                ))
+                            })?;
                         if new_entry.is_free() {
                             let encoded = entry.encode(key_size);
                             new_file.write_all_at(&encoded, new_offset)?;
@@ -484,14 +406,18 @@ impl<T: BucketValue> Bucket<T> {
                     }
                 }
             }
+            // The expanded file must be complete on disk before it replaces the old one
+            new_file.sync_all()?;
         }
-        rename(&tmp_path, &self.dir.join(DEFAULT_FILE_NAME.to_owned()))?;
+        rename(&tmp_path, self.dir.join(DEFAULT_FILE_NAME))?;
+        sync_dir(&self.dir)?;
 
         let path = self.dir.join(DEFAULT_FILE_NAME);
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)?;
         let mut inner_data_with_write_lock = self.inner_data.write().unwrap();
         inner_data_with_write_lock.file = file;
@@ -537,7 +463,13 @@ mod tests {
         let value_size = 12; // TestValue takes 12 bytes
         let init_entry_num = 16;
 
-        let bucket = Bucket::<TestValue>::new(dir.path(), key_size, value_size, init_entry_num)?;
+        let bucket = Bucket::<TestValue>::new(
+            dir.path(),
+            key_size,
+            value_size,
+            init_entry_num,
+            KeyHash::Legacy,
+        )?;
 
         // put
         let key = b"key00001".to_vec();
@@ -559,6 +491,162 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_bucket_put_returns_replaced_value() -> Result<(), BucketError> {
+        let dir = tempdir().unwrap();
+        let bucket = Bucket::<TestValue>::new(dir.path(), 8, 12, 16, KeyHash::Legacy)?;
+
+        let key = b"key00001".to_vec();
+        let first = TestValue { a: 1, b: 1 };
+        let second = TestValue { a: 2, b: 2 };
+        assert_eq!(bucket.put(key.clone(), first.clone())?, None);
+        assert_eq!(bucket.put(key.clone(), second.clone())?, Some(first));
+        assert_eq!(bucket.get(&key)?, Some(second.clone()));
+
+        // A deleted key has nothing to replace
+        bucket.del(&key)?;
+        assert_eq!(bucket.put(key.clone(), second)?, None);
+
+        Ok(())
+    }
+
+    fn key(i: u64) -> Vec<u8> {
+        format!("{:0>8}", i).into_bytes()
+    }
+
+    #[test]
+    fn test_bucket_update_does_not_use_freed_slot() -> Result<(), BucketError> {
+        let dir = tempdir().unwrap();
+        // Four slots, every probe window covers the whole bucket
+        let bucket = Bucket::<TestValue>::new(dir.path(), 8, 12, 4, KeyHash::Legacy)?;
+
+        for i in 0..4 {
+            bucket.put(key(i), TestValue { a: i, b: 0 })?;
+        }
+        bucket.del(&key(0))?;
+
+        // Updating must overwrite the existing copy, whichever slot comes first
+        for i in 1..4 {
+            let replaced = bucket.put(key(i), TestValue { a: i, b: 1 })?;
+            assert_eq!(replaced, Some(TestValue { a: i, b: 0 }));
+        }
+
+        // So the freed slot is still available
+        bucket.put(key(4), TestValue { a: 4, b: 0 })?;
+        assert!(matches!(
+            bucket.put(key(5), TestValue { a: 5, b: 0 }),
+            Err(BucketError::MaxSearchReached)
+        ));
+
+        // And a delete leaves no stale copy behind
+        for i in 1..4 {
+            assert_eq!(bucket.del(&key(i))?, Some(TestValue { a: i, b: 1 }));
+            assert_eq!(bucket.get(&key(i))?, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_delete_reinsert_interleavings() -> Result<(), BucketError> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        use std::collections::HashMap;
+
+        for (seed, entry_num) in [(1, 4), (2, 8), (3, 16), (4, 64)] {
+            let dir = tempdir().unwrap();
+            let bucket = Bucket::<TestValue>::new(dir.path(), 8, 12, entry_num, KeyHash::Legacy)?;
+            let mut model: HashMap<Vec<u8>, TestValue> = HashMap::new();
+            let mut rng = StdRng::seed_from_u64(seed);
+            let key_count = entry_num as u64;
+
+            for step in 0..2_000u32 {
+                let k = key(rng.gen_range(0..key_count));
+                if rng.gen_bool(0.5) {
+                    let value = TestValue { a: step as u64, b: step };
+                    match bucket.put(k.clone(), value.clone()) {
+                        Ok(replaced) => assert_eq!(replaced, model.insert(k, value)),
+                        Err(BucketError::MaxSearchReached) => {
+                            assert!(!model.contains_key(&k), "update of existing key failed")
+                        }
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    assert_eq!(bucket.del(&k)?, model.remove(&k));
+                }
+
+                for i in 0..key_count {
+                    assert_eq!(bucket.get(&key(i))?, model.get(&key(i)).cloned());
+                }
+                // No key is ever stored twice
+                assert_eq!(bucket.entries()?.len(), model.len());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_entries() -> Result<(), BucketError> {
+        let dir = tempdir().unwrap();
+        let bucket = Bucket::<TestValue>::new(dir.path(), 8, 12, 16, KeyHash::Legacy)?;
+
+        for i in 0..8u64 {
+            let key = format!("{:0>8}", i).into_bytes();
+            bucket.put(key, TestValue { a: i, b: i as u32 })?;
+        }
+        bucket.del(b"00000003")?;
+
+        let mut entries = bucket.entries()?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        let keys: Vec<Vec<u8>> = entries.iter().map(|(key, _)| key.clone()).collect();
+        let expected: Vec<Vec<u8>> = [0, 1, 2, 4, 5, 6, 7]
+            .iter()
+            .map(|i| format!("{:0>8}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(entries[3].1, TestValue { a: 4, b: 4 });
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_entries_chunked_and_corrupt() -> Result<(), BucketError> {
+        let dir = tempdir().unwrap();
+        let bucket = Bucket::<TestValue>::new(dir.path(), 8, 12, 4096, KeyHash::Legacy)?;
+        for i in 0..1500u64 {
+            bucket.put(key(i), TestValue { a: i, b: 0 })?;
+        }
+        assert_eq!(bucket.entries()?.len(), 1500);
+
+        // An invalid entry state past the first chunk is reported, not a panic
+        let file = OpenOptions::new().write(true).open(dir.path().join(DEFAULT_FILE_NAME))?;
+        file.write_all_at(&[7], 3000 * Entry::<TestValue>::entry_size(8, 12) as u64)?;
+        assert!(matches!(bucket.entries(), Err(BucketError::Other(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bucket_expand_with_corrupt_entry() -> Result<(), BucketError> {
+        let dir = tempdir().unwrap();
+        let bucket = Bucket::<TestValue>::new(dir.path(), 8, 12, 16, KeyHash::Legacy)?;
+        for i in 0..4u64 {
+            bucket.put(key(i), TestValue { a: i, b: 0 })?;
+        }
+
+        // An invalid entry state fails the expansion instead of panicking
+        let file = OpenOptions::new().write(true).open(dir.path().join(DEFAULT_FILE_NAME))?;
+        file.write_all_at(&[7], 5 * Entry::<TestValue>::entry_size(8, 12) as u64)?;
+        assert!(matches!(bucket.expand(), Err(BucketError::Other(_))));
+
+        // The bucket is left as it was
+        file.write_all_at(&[0], 5 * Entry::<TestValue>::entry_size(8, 12) as u64)?;
+        assert_eq!(bucket.entries()?.len(), 4);
+        bucket.expand()?;
+        for i in 0..4u64 {
+            assert_eq!(bucket.get(&key(i))?, Some(TestValue { a: i, b: 0 }));
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_bucket_expand_fixed_key_size() -> Result<(), BucketError> {
         let dir = tempdir().unwrap();
@@ -566,7 +654,13 @@ mod tests {
         let value_size = 12;
         let init_entry_num = 4; // Small capacity to trigger expand
 
-        let bucket = Bucket::<TestValue>::new(dir.path(), key_size, value_size, init_entry_num)?;
+        let bucket = Bucket::<TestValue>::new(
+            dir.path(),
+            key_size,
+            value_size,
+            init_entry_num,
+            KeyHash::Legacy,
+        )?;
 
         // Insert 4 values
         for i in 0..4 {
@@ -1,4 +1,5 @@
 use crate::kv::index::bucket::{Bucket, BucketError, BucketValue};
+use crate::kv::index::hash::KeyHash;
 use crate::kv::utils::create_dir_if_not_exists;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
@@ -9,23 +10,61 @@ use std::{fmt, io};
 
 const DEFAULT_BUCKET_COUNT: u32 = 32;
 
-#[derive(Serialize, Deserialize)]
-struct BucketMeta {
-    path: String,
-    exists: bool,
-}
-
 #[derive(Serialize, Deserialize)]
 struct BucketsMeta {
     bucket_count: u32,
     key_size: u32,
+    // Absent in stores created before values could be inlined
+    #[serde(default)]
+    inline_value_size: u32,
+    #[serde(default)]
+    max_key_size: u32,
+    // Absent in stores created before the hash was recorded, see `KeyHash::Legacy`
+    #[serde(default)]
+    hash: KeyHash,
+}
+
+impl BucketsMeta {
+    /// Check that the options used to reopen the buckets match the stored layout
+    fn check(&self, opts: &BucketsOptions) -> Result<(), BucketsError> {
+        let checks = [
+            ("key_size", self.key_size, opts.key_size),
+            ("bucket_count", self.bucket_count, opts.bucket_count),
+            ("inline_value_size", self.inline_value_size, opts.inline_value_size),
+            ("max_key_size", self.max_key_size, opts.max_key_size),
+        ];
+        for (option, stored, requested) in checks {
+            if stored != requested {
+                return Err(BucketsError::OptionsMismatch {
+                    option,
+                    stored: stored.to_string(),
+                    requested: requested.to_string(),
+                });
+            }
+        }
+        let pre_hashed = self.hash == KeyHash::PreHashed;
+        if pre_hashed != opts.pre_hashed {
+            return Err(BucketsError::OptionsMismatch {
+                option: "pre_hashed",
+                stored: pre_hashed.to_string(),
+                requested: opts.pre_hashed.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Bytes reserved for the value of an entry
+    fn value_size<T: BucketValue>(&self) -> u32 {
+        T::ENCODED_SIZE + self.inline_value_size
+    }
 }
 
 pub struct Buckets<T: BucketValue> {
     buckets: boxcar::Vec<RwLock<Bucket<T>>>,
-    key_size: u32,
     bucket_count: u32,
-    base_dir: PathBuf,
+    inline_value_size: u32,
+    max_key_size: u32,
+    hash: KeyHash,
 }
 
 #[derive(Debug)]
@@ -34,6 +73,12 @@ pub enum BucketsError {
     Other(String),
     InvalidKeyLength,
     MaxSearchReached,
+    /// The options requested on open differ from the ones stored in meta.json
+    OptionsMismatch {
+        option: &'static str,
+        stored: String,
+        requested: String,
+    },
 }
 
 impl From<BucketError> for BucketsError {
@@ -54,6 +99,15 @@ impl fmt::Display for BucketsError {
             BucketsError::Other(s) => write!(f, "Unknown error: {}", s),
             BucketsError::InvalidKeyLength => write!(f, "Key size does not match"),
             BucketsError::MaxSearchReached => write!(f, "Max search limit reached"),
+            BucketsError::OptionsMismatch {
+                option,
+                stored,
+                requested,
+            } => write!(
+                f,
+                "Option {} mismatch: stored {}, requested {}",
+                option, stored, requested
+            ),
         }
     }
 }
@@ -77,6 +131,15 @@ pub struct BucketsOptions {
     pub key_size: u32,
     pub bucket_count: u32,
     pub init_entry_num_for_each_bucket: u32,
+    /// Extra bytes reserved in every entry for values stored inline, 0 disables inlining
+    pub inline_value_size: u32,
+    /// Accept keys of any length up to this size instead of exactly `key_size`, 0 disables it.
+    /// Keys that do not fit in `key_size` bytes keep a fingerprint in the entry.
+    pub max_key_size: u32,
+    /// The keys are uniformly distributed, e.g. SHA-256 digests: bucket and slot are taken
+    /// from their first 8 bytes instead of hashing them. Requires fixed-size keys
+    /// of at least 8 bytes.
+    pub pre_hashed: bool,
 }
 
 impl Default for BucketsOptions {
@@ -85,6 +148,9 @@ impl Default for BucketsOptions {
             key_size: 32,
             bucket_count: DEFAULT_BUCKET_COUNT,
             init_entry_num_for_each_bucket: 1024,
+            inline_value_size: 0,
+            max_key_size: 0,
+            pre_hashed: false,
         }
     }
 }
@@ -95,10 +161,11 @@ impl<T: BucketValue + Clone> Buckets<T> {
         let base_dir = base_dir.as_ref().to_path_buf();
         let meta_path = base_dir.join("meta.json");
 
-        let mut buckets = boxcar::Vec::with_capacity(opts.bucket_count as usize);
-        let mut meta: BucketsMeta = if meta_path.exists() {
+        let meta: BucketsMeta = if meta_path.exists() {
             let file = File::open(&meta_path)?;
-            serde_json::from_reader(file)?
+            let meta: BucketsMeta = serde_json::from_reader(file)?;
+            meta.check(&opts)?;
+            meta
         } else {
             // Metadata does not exist, create a new one
             // Write metadata file to ensure recovery on restart
@@ -110,47 +177,85 @@ impl<T: BucketValue + Clone> Buckets<T> {
             let meta = BucketsMeta {
                 bucket_count: opts.bucket_count,
                 key_size: opts.key_size,
+                inline_value_size: opts.inline_value_size,
+                max_key_size: opts.max_key_size,
+                hash: if opts.pre_hashed {
+                    KeyHash::PreHashed
+                } else {
+                    KeyHash::random()
+                },
             };
             serde_json::to_writer_pretty(file, &meta)?;
             meta
         };
 
+        let buckets = boxcar::Vec::with_capacity(meta.bucket_count as usize);
         for i in 0..meta.bucket_count {
-            let path = base_dir.join(format!("bucket_{:05}.data", i));
+            let path = Self::bucket_dir(&base_dir, i);
             create_dir_if_not_exists(path.clone())?;
             // If file already exists, restore
             let bucket = Bucket::new(
                 &path,
-                opts.key_size,
-                size_of::<T>() as u32,
+                meta.key_size,
+                meta.value_size::<T>(),
                 opts.init_entry_num_for_each_bucket,
+                meta.hash,
             )?;
             buckets.push(RwLock::new(bucket));
         }
 
         Ok(Self {
             buckets,
-            key_size: opts.key_size,
-            bucket_count: opts.bucket_count,
-            base_dir,
+            bucket_count: meta.bucket_count,
+            inline_value_size: meta.inline_value_size,
+            max_key_size: meta.max_key_size,
+            hash: meta.hash,
         })
     }
 
-    fn hash_key(&self, key: &[u8]) -> usize {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
+    /// Open existing buckets with the layout stored in meta.json, without write access
+    pub fn open_read_only<P: AsRef<Path>>(base_dir: P) -> Result<Self, BucketsError> {
+        let base_dir = base_dir.as_ref();
+        let file = File::open(base_dir.join("meta.json"))?;
+        let meta: BucketsMeta = serde_json::from_reader(file)?;
+
+        let buckets = boxcar::Vec::with_capacity(meta.bucket_count as usize);
+        for i in 0..meta.bucket_count {
+            let bucket = Bucket::open_read_only(
+                Self::bucket_dir(base_dir, i),
+                meta.key_size,
+                meta.value_size::<T>(),
+                meta.hash,
+            )?;
+            buckets.push(RwLock::new(bucket));
+        }
+
+        Ok(Self {
+            buckets,
+            bucket_count: meta.bucket_count,
+            inline_value_size: meta.inline_value_size,
+            max_key_size: meta.max_key_size,
+            hash: meta.hash,
+        })
+    }
 
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % self.bucket_count as usize
+    fn bucket_dir(base_dir: &Path, idx: u32) -> PathBuf {
+        base_dir.join(format!("bucket_{:05}.data", idx))
     }
 
-    pub fn put(&self, key: Vec<u8>, value: T) -> Result<(), BucketsError> {
+    fn hash_key(&self, key: &[u8]) -> usize {
+        self.hash.bucket(key, self.bucket_count)
+    }
+
+    // The write lock serializes mutations within a bucket
+    #[allow(clippy::readonly_write_lock)]
+    /// Insert or update a key, returns the replaced value if the key existed
+    pub fn put(&self, key: Vec<u8>, value: T) -> Result<Option<T>, BucketsError> {
         loop {
             let idx = self.hash_key(&key);
-            let mut bucket = self.buckets[idx].write().unwrap();
+            let bucket = self.buckets[idx].write().unwrap();
             match bucket.put(key.clone(), value.clone()) {
-                Ok(_) => return Ok(()),
+                Ok(replaced) => return Ok(replaced),
                 Err(err) => {
                     match err {
                         BucketError::MaxSearchReached => {
@@ -171,9 +276,40 @@ impl<T: BucketValue + Clone> Buckets<T> {
         Ok(bucket.get(key)?)
     }
 
-    pub fn del(&self, key: &Vec<u8>) -> Result<Option<T>, BucketsError> {
+    /// Flush all bucket files to disk
+    pub fn sync(&self) -> Result<(), BucketsError> {
+        for (_, bucket) in self.buckets.iter() {
+            bucket.read().unwrap().sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn bucket_count(&self) -> u32 {
+        self.bucket_count
+    }
+
+    pub fn inline_value_size(&self) -> u32 {
+        self.inline_value_size
+    }
+
+    pub fn max_key_size(&self) -> u32 {
+        self.max_key_size
+    }
+
+    pub fn pre_hashed(&self) -> bool {
+        self.hash == KeyHash::PreHashed
+    }
+
+    /// Read all occupied entries of the bucket at `idx`
+    pub fn bucket_entries(&self, idx: usize) -> Result<Vec<(Vec<u8>, T)>, BucketsError> {
+        let bucket = self.buckets[idx].read().unwrap();
+        Ok(bucket.entries()?)
+    }
+
+    #[allow(clippy::readonly_write_lock)]
+    pub fn del(&self, key: &[u8]) -> Result<Option<T>, BucketsError> {
         let idx = self.hash_key(key);
-        let mut bucket = self.buckets[idx].write().unwrap();
+        let bucket = self.buckets[idx].write().unwrap();
         Ok(bucket.del(key)?)
     }
 }
@@ -236,6 +372,97 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_buckets_reopen_with_mismatched_options() -> Result<(), BucketsError> {
+        let dir = tempdir().unwrap();
+        let opts = BucketsOptions {
+            key_size: 16,
+            bucket_count: 4,
+            init_entry_num_for_each_bucket: 64,
+            inline_value_size: 0,
+            max_key_size: 0,
+            pre_hashed: false,
+        };
+        {
+            let buckets = Buckets::<TestValue>::new(dir.path(), opts.clone())?;
+            assert_eq!(buckets.bucket_count(), 4);
+            buckets.put(vec![1u8; 16], TestValue { a: 1, b: 2 })?;
+        }
+
+        let buckets = Buckets::<TestValue>::new(dir.path(), opts.clone())?;
+        assert_eq!(buckets.get(&[1u8; 16])?, Some(TestValue { a: 1, b: 2 }));
+        drop(buckets);
+
+        let result = Buckets::<TestValue>::new(
+            dir.path(),
+            BucketsOptions {
+                bucket_count: 8,
+                ..opts.clone()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(BucketsError::OptionsMismatch {
+                option: "bucket_count",
+                ..
+            })
+        ));
+
+        let result = Buckets::<TestValue>::new(dir.path(), BucketsOptions::default());
+        assert!(matches!(
+            result,
+            Err(BucketsError::OptionsMismatch {
+                option: "key_size",
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buckets_hash_recorded_in_meta() -> Result<(), BucketsError> {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let dir = tempdir().unwrap();
+        let meta_path = dir.path().join("meta.json");
+        let keys: Vec<Vec<u8>> = (0..100).map(|i| format!("{:0>32}", i).into_bytes()).collect();
+        {
+            let buckets = Buckets::<TestValue>::new(dir.path(), BucketsOptions::default())?;
+            for (i, key) in keys.iter().enumerate() {
+                buckets.put(key.clone(), TestValue { a: i as u64, b: 0 })?;
+            }
+        }
+        let meta: serde_json::Value = serde_json::from_reader(File::open(&meta_path)?)?;
+        assert_eq!(meta["hash"]["algorithm"], "siphash13");
+        let buckets = Buckets::<TestValue>::new(dir.path(), BucketsOptions::default())?;
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(buckets.get(key)?, Some(TestValue { a: i as u64, b: 0 }));
+        }
+        drop(buckets);
+
+        // A store created before the hash was recorded keeps routing keys with DefaultHasher
+        let dir = tempdir().unwrap();
+        let meta_path = dir.path().join("meta.json");
+        drop(Buckets::<TestValue>::new(dir.path(), BucketsOptions::default())?);
+        let mut meta: serde_json::Value = serde_json::from_reader(File::open(&meta_path)?)?;
+        meta.as_object_mut().unwrap().remove("hash");
+        serde_json::to_writer(File::create(&meta_path)?, &meta)?;
+
+        let buckets = Buckets::<TestValue>::new(dir.path(), BucketsOptions::default())?;
+        for key in &keys {
+            buckets.put(key.clone(), TestValue { a: 0, b: 0 })?;
+        }
+        for key in &keys {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            let idx = (hasher.finish() % buckets.bucket_count() as u64) as usize;
+            assert!(buckets.bucket_entries(idx)?.iter().any(|(k, _)| k == key));
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_buckets_large_data() -> Result<(), BucketsError> {
         let dir = tempdir().unwrap();
@@ -263,7 +490,7 @@ mod tests {
         }
 
         // Delete a subset
-        for (i, (key, _)) in keys.iter().enumerate().take(1000) {
+        for (key, _) in keys.iter().take(1000) {
             buckets.del(key)?;
             let result = buckets.get(key)?;
             assert!(result.is_none(), "key {:?} should be deleted", key);
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher13;
+use std::hash::Hasher;
+
+/// Hash function of the keys, recorded in meta.json so that routing to a bucket and
+/// probing within it never change for an existing store
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum KeyHash {
+    /// Used by stores created before the hash was recorded: `DefaultHasher` of the key on
+    /// 64-bit targets, i.e. SipHash-1-3 with zero keys over the key prefixed with its
+    /// length as a little-endian u64, pinned here
+    #[default]
+    Legacy,
+    /// SipHash-1-3 of the key bytes, seeded randomly when the store is created
+    #[serde(rename = "siphash13")]
+    SipHash13 { key0: u64, key1: u64 },
+    /// The keys are uniformly distributed already, e.g. digests: their first 8 bytes
+    /// are taken as the hash, see `BucketsOptions::pre_hashed`
+    PreHashed,
+}
+
+impl KeyHash {
+    /// SipHash-1-3 with a random seed, so colliding keys cannot be crafted in advance
+    pub fn random() -> Self {
+        KeyHash::SipHash13 {
+            key0: rand::random(),
+            key1: rand::random(),
+        }
+    }
+
+    /// Hash of a key, the slot it is probed from is this modulo the bucket size
+    pub fn hash(&self, key: &[u8]) -> u64 {
+        match *self {
+            KeyHash::Legacy => {
+                // Written out rather than through `Hash for [u8]`, whose length prefix
+                // depends on the target's usize
+                let mut hasher = SipHasher13::new_with_keys(0, 0);
+                hasher.write(&(key.len() as u64).to_le_bytes());
+                hasher.write(key);
+                hasher.finish()
+            }
+            KeyHash::SipHash13 { key0, key1 } => {
+                let mut hasher = SipHasher13::new_with_keys(key0, key1);
+                hasher.write(key);
+                hasher.finish()
+            }
+            KeyHash::PreHashed => {
+                let mut bytes = [0u8; 8];
+                let len = key.len().min(8);
+                bytes[..len].copy_from_slice(&key[..len]);
+                u64::from_le_bytes(bytes)
+            }
+        }
+    }
+
+    /// Index of the bucket holding a key
+    pub fn bucket(&self, key: &[u8], bucket_count: u32) -> usize {
+        let hash = self.hash(key);
+        let hash = match self {
+            KeyHash::Legacy => hash,
+            // The high half, so the keys of a bucket spread over all of its slots
+            KeyHash::SipHash13 { .. } | KeyHash::PreHashed => hash >> 32,
+        };
+        (hash % bucket_count as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_legacy_hash_matches_default_hasher() {
+        for key in [b"".to_vec(), b"key".to_vec(), vec![7u8; 32], vec![1u8; 100]] {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            assert_eq!(KeyHash::Legacy.hash(&key), hasher.finish());
+        }
+    }
+
+    #[test]
+    fn test_seeded_hash() {
+        let hash = KeyHash::SipHash13 { key0: 1, key1: 2 };
+        assert_eq!(hash.hash(b"key"), hash.hash(b"key"));
+        assert_ne!(
+            hash.hash(b"key"),
+            KeyHash::SipHash13 { key0: 1, key1: 3 }.hash(b"key")
+        );
+        assert_ne!(KeyHash::random(), KeyHash::random());
+
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, r#"{"algorithm":"siphash13","key0":1,"key1":2}"#);
+        assert_eq!(serde_json::from_str::<KeyHash>(&json).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_pre_hashed_takes_key_bits() {
+        let key: Vec<u8> = (1..=32).collect();
+        assert_eq!(KeyHash::PreHashed.hash(&key), 0x0807060504030201);
+        // Bytes 4..8 pick the bucket, the low bytes the slot
+        assert_eq!(KeyHash::PreHashed.bucket(&key, 1 << 16), 0x0605);
+        assert_eq!(KeyHash::PreHashed.hash(&[1, 2]), 0x0201);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::kv::key_layout::StoredKey;
+use crate::kv::{KV, KVError, KVOp};
+use std::collections::{HashMap, HashSet};
+use std::vec;
+
+type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Iterator over all live key-value pairs.
+///
+/// Buffered writes are snapshotted when the iterator is created and shadow the
+/// on-disk state. Buckets are read one at a time, so writes flushed while
+/// iterating may or may not be observed, but no key is returned twice.
+pub struct Iter<'a> {
+    kv: &'a KV,
+    // Keys with a buffered put or delete when the iterator was created
+    buffered_keys: HashSet<Vec<u8>>,
+    pending: vec::IntoIter<KeyValue>,
+    next_bucket: u32,
+    with_values: bool,
+    done: bool,
+}
+
+/// Iterator over all live keys, see [`Iter`]
+pub struct Keys<'a> {
+    inner: Iter<'a>,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(kv: &'a KV, with_values: bool) -> Self {
+        let mut buffered = HashMap::new();
+        {
+            // Same lock order as KV::batch, so the snapshot is consistent
+            let current_buffer = kv.current_buffer.read().unwrap();
+            let flushing_buffers = kv.flushing_buffers.read().unwrap();
+            // Oldest first, so newer operations override older ones
+            for buffer in flushing_buffers
+                .iter()
+                .map(|flushing_buffer| &flushing_buffer.buffer)
+                .chain(std::iter::once(&*current_buffer))
+            {
+                for (key, op) in buffer {
+                    buffered.insert(key.clone(), op_value(op, with_values));
+                }
+            }
+        }
+
+        let buffered_keys = buffered.keys().cloned().collect();
+        let pending: Vec<KeyValue> = buffered
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+
+        Self {
+            kv,
+            buffered_keys,
+            pending: pending.into_iter(),
+            next_bucket: 0,
+            with_values,
+            done: false,
+        }
+    }
+
+    /// Load the live entries of the next bucket that are not shadowed by the snapshot
+    fn load_bucket(&self, idx: u32) -> Result<Vec<KeyValue>, KVError> {
+        // Held while reading pages, so the flush thread cannot free them under us
+        let flushing_buffers = self.kv.flushing_buffers.read().unwrap();
+        let entries = self.kv.buckets_index.bucket_entries(idx as usize)?;
+
+        let mut items = Vec::with_capacity(entries.len());
+        'entries: for (stored_key, data_info) in entries {
+            // A long key is read back from the front of its value
+            let (key, long_key_value) = match self.kv.key_layout.decode(stored_key) {
+                Some(StoredKey::Key(key)) => (key, None),
+                Some(StoredKey::Long { key_len }) => {
+                    let mut value = data_info.read(&self.kv.level_page_bitmap)?;
+                    let key: Vec<u8> = value.drain(..key_len.min(value.len())).collect();
+                    (key, Some(value))
+                }
+                None => return Err(KVError::Other("Corrupt key in key store".to_string())),
+            };
+            if self.buffered_keys.contains(&key) {
+                continue;
+            }
+            // Rotated after the snapshot was taken, newest first
+            for flushing_buffer in flushing_buffers.iter().rev() {
+                if let Some(op) = flushing_buffer.buffer.get(&key) {
+                    if let Some(value) = op_value(op, self.with_values) {
+                        items.push((key, value));
+                    }
+                    continue 'entries;
+                }
+            }
+
+            if !self.with_values {
+                items.push((key, Vec::new()));
+            } else if let Some(value) = long_key_value {
+                items.push((key, value));
+            } else if let Some(value) = self.kv.cache.get(&key) {
+                items.push((key, value));
+            } else {
+                items.push((key, data_info.read(&self.kv.level_page_bitmap)?));
+            }
+        }
+        Ok(items)
+    }
+}
+
+/// Value of a buffered operation, values are left empty when not requested
+fn op_value(op: &KVOp, with_value: bool) -> Option<Vec<u8>> {
+    match op {
+        KVOp::Put { value } if with_value => Some(value.clone()),
+        KVOp::Put { .. } => Some(Vec::new()),
+        KVOp::Del {} => None,
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<KeyValue, KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.next() {
+                return Some(Ok(item));
+            }
+            if self.done || self.next_bucket >= self.kv.buckets_index.bucket_count() {
+                return None;
+            }
+            let idx = self.next_bucket;
+            self.next_bucket += 1;
+            match self.load_bucket(idx) {
+                Ok(items) => self.pending = items.into_iter(),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Keys<'a> {
+    pub(crate) fn new(kv: &'a KV) -> Self {
+        Self {
+            inner: Iter::new(kv, false),
+        }
+    }
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Result<Vec<u8>, KVError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|item| item.map(|(key, _)| key))
+    }
+}
@@ -0,0 +1,146 @@
+use crate::kv::KVError;
+use sha2::{Digest, Sha256};
+
+/// Largest key accepted with variable-length keys
+pub const MAX_KEY_SIZE: usize = 64 * 1024;
+
+// Variable-length keys are stored in the key field of a bucket entry as
+// `[tag u8][key_len u32]` followed by the key, or by a fingerprint of a key too long to fit
+const KEY_HEADER_LEN: usize = 5;
+const KEY_INLINE: u8 = 0;
+const KEY_FINGERPRINT: u8 = 1;
+const MIN_FINGERPRINT_LEN: usize = 16;
+
+/// Smallest `key_size` accepted with variable-length keys
+pub const MIN_VARIABLE_KEY_SIZE: u32 = (KEY_HEADER_LEN + MIN_FINGERPRINT_LEN) as u32;
+
+/// How user keys map to the fixed-size keys of the bucket entries
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum KeyLayout {
+    /// Every key is exactly `key_size` bytes and stored as is
+    Fixed { key_size: u32 },
+    /// Keys up to `max_key_size` bytes. A key too long for the key field keeps a
+    /// fingerprint there, and its bytes are stored in the value store in front of the value.
+    Variable { key_size: u32, max_key_size: u32 },
+}
+
+/// Key read back from a bucket entry
+pub(crate) enum StoredKey {
+    Key(Vec<u8>),
+    /// Only the length is known, the key is the first `key_len` bytes of the stored value
+    Long {
+        key_len: usize,
+    },
+}
+
+impl KeyLayout {
+    /// Layout for `BucketsOptions::key_size` and `max_key_size`, 0 means fixed-size keys
+    pub fn new(key_size: u32, max_key_size: u32) -> Result<Self, KVError> {
+        if max_key_size == 0 {
+            return Ok(KeyLayout::Fixed { key_size });
+        }
+        if key_size < MIN_VARIABLE_KEY_SIZE {
+            return Err(KVError::InvalidOptions(format!(
+                "key_size must be at least {} with variable-length keys",
+                MIN_VARIABLE_KEY_SIZE
+            )));
+        }
+        if max_key_size as usize > MAX_KEY_SIZE {
+            return Err(KVError::InvalidOptions(format!(
+                "max_key_size must be at most {}",
+                MAX_KEY_SIZE
+            )));
+        }
+        Ok(KeyLayout::Variable {
+            key_size,
+            max_key_size,
+        })
+    }
+
+    pub fn key_size(&self) -> u32 {
+        match *self {
+            KeyLayout::Fixed { key_size } | KeyLayout::Variable { key_size, .. } => key_size,
+        }
+    }
+
+    pub fn is_valid(&self, key: &[u8]) -> bool {
+        match *self {
+            KeyLayout::Fixed { key_size } => key.len() == key_size as usize,
+            KeyLayout::Variable { max_key_size, .. } => key.len() <= max_key_size as usize,
+        }
+    }
+
+    /// Whether the key is stored in the value store, in front of its value
+    pub fn is_long(&self, key: &[u8]) -> bool {
+        match *self {
+            KeyLayout::Fixed { .. } => false,
+            KeyLayout::Variable { key_size, .. } => key.len() + KEY_HEADER_LEN > key_size as usize,
+        }
+    }
+
+    /// Key of the bucket entry of `key`
+    pub fn encode(&self, key: &[u8]) -> Vec<u8> {
+        let key_size = match *self {
+            KeyLayout::Fixed { .. } => return key.to_vec(),
+            KeyLayout::Variable { key_size, .. } => key_size as usize,
+        };
+        let mut stored = Vec::with_capacity(key_size);
+        if self.is_long(key) {
+            stored.push(KEY_FINGERPRINT);
+            stored.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            let fingerprint = Sha256::digest(key);
+            let len = fingerprint.len().min(key_size - KEY_HEADER_LEN);
+            stored.extend_from_slice(&fingerprint[..len]);
+        } else {
+            stored.push(KEY_INLINE);
+            stored.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            stored.extend_from_slice(key);
+        }
+        stored.resize(key_size, 0);
+        stored
+    }
+
+    /// Decode the key of a bucket entry, None if it is malformed
+    pub fn decode(&self, stored: Vec<u8>) -> Option<StoredKey> {
+        if let KeyLayout::Fixed { .. } = self {
+            return Some(StoredKey::Key(stored));
+        }
+        let tag = *stored.first()?;
+        let key_len = u32::from_le_bytes(stored.get(1..KEY_HEADER_LEN)?.try_into().ok()?) as usize;
+        match tag {
+            KEY_INLINE => {
+                let key = stored.get(KEY_HEADER_LEN..KEY_HEADER_LEN + key_len)?;
+                Some(StoredKey::Key(key.to_vec()))
+            }
+            KEY_FINGERPRINT => Some(StoredKey::Long { key_len }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variable_key_round_trip() {
+        let layout = KeyLayout::new(32, 1024).unwrap();
+        for len in [0, 1, 27, 28, 1024] {
+            let key: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let stored = layout.encode(&key);
+            assert_eq!(stored.len(), 32);
+            assert_eq!(layout.is_long(&key), len > 27);
+            match layout.decode(stored).unwrap() {
+                StoredKey::Key(decoded) => assert_eq!(decoded, key),
+                StoredKey::Long { key_len } => assert_eq!(key_len, len),
+            }
+        }
+        assert_ne!(layout.encode(&[1u8; 100]), layout.encode(&[2u8; 100]));
+        assert_ne!(layout.encode(&[1u8; 100]), layout.encode(&[1u8; 101]));
+
+        assert!(layout.is_valid(b""));
+        assert!(!layout.is_valid(&[0u8; 1025]));
+        assert!(KeyLayout::new(16, 1024).is_err());
+        assert!(KeyLayout::new(32, MAX_KEY_SIZE as u32 + 1).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::kv::KVError;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+const LOCK_FILE_NAME: &str = "LOCK";
+
+/// `flock` on the LOCK file of a KV directory, released when dropped
+pub(crate) struct DirLock {
+    _file: Option<File>,
+}
+
+impl DirLock {
+    /// Lock `dir` exclusively for a writer, or shared so readers can coexist with each other.
+    ///
+    /// A shared lock never creates the LOCK file, a directory without one was never opened
+    /// by a writer and is left unlocked.
+    pub fn acquire(dir: &Path, exclusive: bool) -> Result<Self, KVError> {
+        let path = dir.join(LOCK_FILE_NAME);
+        let file = if exclusive {
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?
+        } else {
+            match File::open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self { _file: None }),
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let locked = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match locked {
+            Ok(()) => Ok(Self { _file: Some(file) }),
+            Err(TryLockError::WouldBlock) => Err(KVError::Locked),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dir_lock_modes() {
+        let dir = tempdir().unwrap();
+        // No writer has created the LOCK file yet
+        DirLock::acquire(dir.path(), false).unwrap();
+        assert!(!dir.path().join(LOCK_FILE_NAME).exists());
+
+        let writer = DirLock::acquire(dir.path(), true).unwrap();
+        assert!(matches!(
+            DirLock::acquire(dir.path(), true),
+            Err(KVError::Locked)
+        ));
+        assert!(matches!(
+            DirLock::acquire(dir.path(), false),
+            Err(KVError::Locked)
+        ));
+        drop(writer);
+
+        let reader = DirLock::acquire(dir.path(), false).unwrap();
+        let other_reader = DirLock::acquire(dir.path(), false).unwrap();
+        assert!(matches!(
+            DirLock::acquire(dir.path(), true),
+            Err(KVError::Locked)
+        ));
+        drop(reader);
+        drop(other_reader);
+
+        DirLock::acquire(dir.path(), true).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::kv::utils::sync_dir;
 use serde::{Deserialize, Serialize};
 use std::io::Write;
 use std::path::Path;
@@ -7,7 +8,13 @@ use std::{fs, io};
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Meta {
     pub current_wal_id: u64,
+    /// Checkpoint of the flush thread: id of the WAL whose changes may not be synced to the stores yet
+    #[serde(default)]
+    pub flushing_wal_id: Option<u64>,
     pub key_size: u32,
+    /// Sequence number of the last batch written to a WAL before `current_wal_id`
+    #[serde(default)]
+    pub last_seq: u64,
 }
 
 impl Meta {
@@ -18,18 +25,18 @@ impl Meta {
         Ok(meta)
     }
 
-    /// Save Meta to file
+    /// Save Meta to file atomically: write a temporary file, then rename it over the old one
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
         let json = serde_json::to_string_pretty(self)?;
-        let mut file = fs::File::create(path)?;
+        let mut file = fs::File::create(&tmp_path)?;
         file.write_all(json.as_bytes())?;
-        file.flush()?;
-        Ok(())
-    }
-
-    /// Update wal_id and save
-    pub fn update_wal_id<P: AsRef<Path>>(&mut self, wal_id: u64, path: P) -> io::Result<()> {
-        self.current_wal_id = wal_id;
-        self.save_to_file(path)
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+            _ => sync_dir("."),
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,52 @@
+use crate::kv::wal::WAL;
+use log::error;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Background thread syncing the current WAL for `Durability::Periodic`
+pub(crate) struct PeriodicSync {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PeriodicSync {
+    pub fn start(wal: Arc<RwLock<WAL>>, interval: Duration) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || {
+            let (stopped, cond) = &*thread_stop;
+            loop {
+                {
+                    let stopped = stopped.lock().unwrap();
+                    let (stopped, _) = cond
+                        .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+                        .unwrap();
+                    if *stopped {
+                        return;
+                    }
+                }
+                // Sync a duplicated handle, writers are not blocked by the fsync
+                let file = wal.read().unwrap().sync_handle();
+                if let Err(e) = file.and_then(|file| file.sync_all()) {
+                    error!("Failed to sync WAL: {:?}", e);
+                }
+            }
+        });
+        Self {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop the thread and wait for it to exit
+    pub fn stop(&mut self) -> thread::Result<()> {
+        let (stopped, cond) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        cond.notify_all();
+        match self.handle.take() {
+            Some(handle) => handle.join(),
+            None => Ok(()),
+        }
+    }
+}
@@ -2,7 +2,6 @@ use std::{fs, io};
 use std::fs::OpenOptions;
 use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
-use rand::Rng;
 
 /// Create a directory if it does not exist
 pub fn create_dir_if_not_exists<P: AsRef<Path>>(path: P) -> io::Result<()> {
@@ -42,13 +41,20 @@ pub fn create_file_with_len<P: AsRef<Path>>(path: P, size: u64) -> io::Result<st
     Ok(file)
 }
 
+/// Sync a directory, making file creations, renames and removals in it durable
+pub fn sync_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
 /// Check if a path exists
 pub fn path_exist(path: &Path) -> io::Result<bool> {
     path.try_exists()
 }
 
 /// Generate 32 random bytes
+#[cfg(test)]
 pub fn random_bytes32() -> [u8; 32] {
+    use rand::Rng;
     let mut rng = rand::thread_rng();
     let mut bytes = [0u8; 32];
     rng.fill(&mut bytes);
@@ -1,101 +1,597 @@
+use crate::kv::utils::sync_dir;
+use crate::kv::{Durability, WALCompression};
+use log::error;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
 
-pub struct WALOptions {
-    fsync: bool,
+const WAL_MAGIC: &[u8; 4] = b"BKWL";
+const WAL_HEADER_LEN: u64 = 8;
+
+/// Version of WAL files without a header, written before checksums were added:
+/// records are `[len u32][payload]`
+pub const LEGACY_WAL_VERSION: u8 = 0;
+
+/// Version of newly created WAL files, stored in the header `[magic][version u8][reserved 3 bytes]`.
+///
+/// - 1: records are `[len u32][crc32 u32][payload]`
+/// - 2: same records, payloads are batches with tagged entries
+/// - 3: same records, payloads start with the codec they are compressed with
+/// - 4: records are `[len u32][crc32 of len u32][crc32 u32][payload]`
+pub const WAL_VERSION: u8 = 4;
+
+/// First version whose batches have tagged entries
+pub const TAGGED_BATCH_WAL_VERSION: u8 = 2;
+
+/// First version with a codec byte per record, records of older versions are all zstd frames
+const CODEC_WAL_VERSION: u8 = 3;
+
+/// First version whose record headers have a checksum of the length, so a corrupt length
+/// is told apart from a record torn at the tail
+const HEADER_CRC_WAL_VERSION: u8 = 4;
+
+// Codec of a record payload: `[codec u8][data]`
+const CODEC_RAW: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+// Followed by `[dictionary id u32]`, the dictionary is stored in the WAL directory
+const CODEC_ZSTD_DICT: u8 = 2;
+
+const DICTIONARY_SUFFIX: &str = ".dict";
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+fn data_offset(version: u8) -> u64 {
+    if version == LEGACY_WAL_VERSION {
+        0
+    } else {
+        WAL_HEADER_LEN
+    }
+}
+
+fn record_header_len(version: u8) -> usize {
+    match version {
+        LEGACY_WAL_VERSION => 4,
+        version if version < HEADER_CRC_WAL_VERSION => 8,
+        _ => 12,
+    }
+}
+
+/// Outcome of replaying a WAL file
+#[derive(Debug, PartialEq)]
+pub struct ReplayResult {
+    /// Length of the file up to the end of the last replayed or skipped record
+    pub valid_len: u64,
+    /// Offset of the first record with a bad checksum, header or payload
+    pub corrupt_offset: Option<u64>,
+    /// Number of corrupt records skipped
+    pub skipped: usize,
 }
 
 /// Simplified WAL: sequential write + partially concurrent write
 pub struct WAL {
     file: File,
+    // Directory holding the dictionaries of the records
+    dir: PathBuf,
     end_offset: u64,
-    fsync: bool,
-}
-
-impl Default for WALOptions {
-    fn default() -> Self {
-        WALOptions { fsync: true }
-    }
+    version: u8,
+    // Bytes of a failed append could not be removed, nothing may be appended after them
+    poisoned: bool,
+    // Makes the next append write this many bytes and fail
+    #[cfg(test)]
+    fail_next_append: Option<usize>,
+    // Makes removing the bytes of a failed append fail
+    #[cfg(test)]
+    fail_cut_back: bool,
 }
 
 impl WAL {
-    /// Open a WAL file
-    pub fn open(path: &Path, fsync: bool) -> io::Result<Self> {
+    /// Open a WAL file, a new file gets a synced header of the current version
+    pub fn open(path: &Path) -> io::Result<Self> {
         let mut file = OpenOptions::new()
             .create(true)
             .append(true) // O_APPEND
             .read(true)
             .open(path)?;
+        let mut end_offset = file.seek(SeekFrom::End(0))?;
+        let version = match read_version(&file, end_offset)? {
+            Some(version) => version,
+            None => {
+                // New file, or a header torn before any record was written
+                file.set_len(0)?;
+                file.write_all(&file_header())?;
+                file.sync_all()?;
+                end_offset = WAL_HEADER_LEN;
+                WAL_VERSION
+            }
+        };
+        Ok(Self {
+            file,
+            dir: parent_dir(path),
+            end_offset,
+            version,
+            poisoned: false,
+            #[cfg(test)]
+            fail_next_append: None,
+            #[cfg(test)]
+            fail_cut_back: false,
+        })
+    }
+
+    /// Open an existing WAL file for replay only, writing to it fails
+    pub fn open_read_only(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
         let end_offset = file.seek(SeekFrom::End(0))?;
+        // A missing or torn header holds no record
+        let version = read_version(&file, end_offset)?.unwrap_or(WAL_VERSION);
         Ok(Self {
             file,
+            dir: parent_dir(path),
             end_offset,
-            fsync,
+            version,
+            poisoned: false,
+            #[cfg(test)]
+            fail_next_append: None,
+            #[cfg(test)]
+            fail_cut_back: false,
         })
     }
-    
-    pub fn flush(&mut self) -> io::Result<()> {
-        if self.fsync {
-            self.file.sync_all()?;
-        }
-        Ok(())
+
+    /// Version of the file, records are always written in the version the file was created with
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Sync the WAL file to disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
     }
 
-    /// Sequentially write a record (maintains mutable reference)
-    pub fn write_record(&mut self, payload: Vec<u8>) -> io::Result<u64> {
-        let payload = compress_data(&payload);
-        let length = payload.len() as u32;
-        let mut buf = Vec::with_capacity(4 + payload.len());
-        buf.extend_from_slice(&length.to_le_bytes());
-        buf.extend_from_slice(&payload);
+    /// Handle to sync the file without holding on to the WAL
+    pub fn sync_handle(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Whether a failed append left bytes behind, the WAL must be rotated before writing again
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Sequentially write records with a single append, synced as requested by `durability`,
+    /// returns the file length after the last record.
+    ///
+    /// If the append or its sync fails the file is cut back to its previous length, so the
+    /// next records never follow a torn or unacknowledged one. If that fails as well the
+    /// WAL is poisoned.
+    pub fn write_records(
+        &mut self,
+        payloads: &[Vec<u8>],
+        compressor: &WALCompressor,
+        durability: Durability,
+    ) -> io::Result<u64> {
+        let mut buf = Vec::new();
+        for payload in payloads {
+            let payload = if self.version < CODEC_WAL_VERSION {
+                zstd::encode_all(payload.as_slice(), LEGACY_ZSTD_LEVEL)?
+            } else {
+                compressor.compress(payload)?
+            };
+            let length = u32::try_from(payload.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "WAL record too large"))?
+                .to_le_bytes();
+            buf.extend_from_slice(&length);
+            if self.version >= HEADER_CRC_WAL_VERSION {
+                buf.extend_from_slice(&crc32fast::hash(&length).to_le_bytes());
+            }
+            if self.version != LEGACY_WAL_VERSION {
+                buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            }
+            buf.extend_from_slice(&payload);
+        }
 
         let offset = self.end_offset;
-        self.file.write(&buf)?;
-        if self.fsync {
-            self.file.sync_all()?;
+        if let Err(e) = self.append(&buf, durability) {
+            // O_APPEND writes always go to the end of the file, cutting it is enough
+            if let Err(truncate_err) = self.cut_back(offset) {
+                error!("Failed to remove a failed WAL append: {:?}", truncate_err);
+                self.poisoned = true;
+            }
+            return Err(e);
         }
         self.end_offset += buf.len() as u64;
         Ok(offset + buf.len() as u64)
     }
 
-    /// Sequentially read WAL and replay
-    pub fn replay<F>(&self, mut callback: F) -> io::Result<()>
+    /// Make the next append write `len` bytes and fail, and removing them fail if `poison`
+    #[cfg(test)]
+    pub(crate) fn fail_next_append(&mut self, len: usize, poison: bool) {
+        self.fail_next_append = Some(len);
+        self.fail_cut_back = poison;
+    }
+
+    fn cut_back(&mut self, len: u64) -> io::Result<()> {
+        #[cfg(test)]
+        if std::mem::take(&mut self.fail_cut_back) {
+            return Err(io::Error::other("Injected WAL truncate failure"));
+        }
+        self.file.set_len(len)
+    }
+
+    fn append(&mut self, buf: &[u8], durability: Durability) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(len) = self.fail_next_append.take() {
+            self.file.write_all(&buf[..len.min(buf.len())])?;
+            return Err(io::Error::other("Injected WAL append failure"));
+        }
+        self.file.write_all(buf)?;
+        match durability {
+            Durability::Sync => self.file.sync_all(),
+            Durability::DataSync => self.file.sync_data(),
+            Durability::None | Durability::Periodic(_) => Ok(()),
+        }
+    }
+
+    /// Drop everything after `len` bytes, e.g. a torn record at the tail
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        if len < self.end_offset {
+            self.file.set_len(len)?;
+            self.file.sync_all()?;
+            self.end_offset = len;
+        }
+        Ok(())
+    }
+
+    /// Iterate over the records of the file from the start
+    pub fn records(&self) -> io::Result<WALRecords> {
+        WALRecords::new(self.file.try_clone()?, self.dir.clone(), self.version)
+    }
+
+    /// Sequentially read WAL and replay every record to `callback`, which returns false
+    /// if it cannot decode the payload.
+    ///
+    /// An incomplete record at the end of the file is a torn write and ends the replay.
+    /// A complete record with a bad checksum, or a payload that cannot be decompressed
+    /// or decoded, is corrupt: it ends the replay unless `skip_corrupt` is set.
+    /// A record whose header is corrupt always ends the replay, the next one cannot be found.
+    /// A record compressed with a dictionary missing from the WAL directory fails the replay.
+    pub fn replay<F>(&self, skip_corrupt: bool, mut callback: F) -> io::Result<ReplayResult>
     where
-        F: FnMut(Vec<u8>),
+        F: FnMut(Vec<u8>) -> bool,
     {
-        let file_len = self.file.metadata()?.len();
-        if file_len == 0 {
-            return Ok(());
+        let mut records = self.records()?;
+        let mut result = ReplayResult {
+            valid_len: records.offset(),
+            corrupt_offset: None,
+            skipped: 0,
+        };
+        for record in &mut records {
+            let record = record?;
+            let replayed = record.payload.is_some_and(&mut callback);
+            if !replayed {
+                result.corrupt_offset.get_or_insert(record.offset);
+                if !skip_corrupt {
+                    break;
+                }
+                result.skipped += 1;
+            }
+            result.valid_len = record.end_offset;
+        }
+        if let Some(offset) = records.corrupt_offset() {
+            result.corrupt_offset.get_or_insert(offset);
         }
+        Ok(result)
+    }
+}
 
-        let mut offset = 0;
-        let mut buf = vec![0u8; file_len as usize];
+/// A complete record read from a WAL file
+#[derive(Debug, PartialEq)]
+pub struct WALRecord {
+    /// Offset of the record in the file
+    pub offset: u64,
+    /// Offset right after the record
+    pub end_offset: u64,
+    /// Decompressed payload, None if the checksum does not match or it cannot be decompressed
+    pub payload: Option<Vec<u8>>,
+}
+
+/// Buffered iterator over the records of a WAL file, only one record is held in memory.
+///
+/// Iteration ends at the end of the file, at an incomplete record, i.e. a torn write,
+/// or at a record whose header is corrupt, see `corrupt_offset`.
+/// A record compressed with a dictionary missing from the WAL directory yields an error.
+pub struct WALRecords {
+    reader: BufReader<File>,
+    // Directory holding the dictionaries of the records
+    dir: PathBuf,
+    version: u8,
+    offset: u64,
+    file_len: u64,
+    // Offset of a record whose length cannot be trusted
+    corrupt_offset: Option<u64>,
+    dictionaries: HashMap<u32, DecoderDictionary<'static>>,
+    done: bool,
+}
+
+impl WALRecords {
+    /// Open a WAL file to read its records, e.g. from a tool inspecting a KV directory
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        // A missing or torn header holds no record
+        let version = read_version(&file, file_len)?.unwrap_or(WAL_VERSION);
+        Self::new(file, parent_dir(path), version)
+    }
+
+    fn new(mut file: File, dir: PathBuf, version: u8) -> io::Result<Self> {
+        let file_len = file.metadata()?.len();
+        let offset = data_offset(version).min(file_len);
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            reader: BufReader::with_capacity(READ_BUFFER_SIZE, file),
+            dir,
+            version,
+            offset,
+            file_len,
+            corrupt_offset: None,
+            dictionaries: HashMap::new(),
+            done: false,
+        })
+    }
+
+    /// Version of the file
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Offset right after the last record read, the length of the valid part of the file
+    /// once the iteration is over
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Offset of the record that ended the iteration because its header is corrupt.
+    ///
+    /// Files older than `HEADER_CRC_WAL_VERSION` have no checksum of the length, a corrupt
+    /// length pointing past the end of the file looks like a torn record there.
+    pub fn corrupt_offset(&self) -> Option<u64> {
+        self.corrupt_offset
+    }
 
-        // 一次性读入整个文件
-        self.file.read_exact_at(&mut buf, 0)?;
+    /// Read the next complete record, None at the end of the file, at a torn record
+    /// or at a corrupt header
+    fn read_record(&mut self) -> io::Result<Option<WALRecord>> {
+        let header_len = record_header_len(self.version);
+        if self.offset + header_len as u64 > self.file_len {
+            return Ok(None);
+        }
+        let mut header = [0u8; 12];
+        let header = &mut header[..header_len];
+        self.reader.read_exact(header)?;
+        if self.version >= HEADER_CRC_WAL_VERSION
+            && crc32fast::hash(&header[..4]) != read_u32(header, 4)
+        {
+            self.corrupt_offset = Some(self.offset);
+            return Ok(None);
+        }
+        let length = read_u32(header, 0) as u64;
+        let end_offset = self.offset + header_len as u64 + length;
+        if end_offset > self.file_len {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.reader.read_exact(&mut payload)?;
 
-        while offset + 4 <= buf.len() {
-            let length = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
-            offset += 4;
+        let checksum_ok = self.version == LEGACY_WAL_VERSION
+            || crc32fast::hash(&payload) == read_u32(header, header_len - 4);
+        let payload = if checksum_ok {
+            self.decompress(&payload)?
+        } else {
+            None
+        };
+        let record = WALRecord {
+            offset: self.offset,
+            end_offset,
+            payload,
+        };
+        self.offset = end_offset;
+        Ok(Some(record))
+    }
 
-            if offset + length > buf.len() {
-                break; // 文件尾部损坏
+    /// Decompress a record payload, None if it is malformed
+    fn decompress(&mut self, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if self.version < CODEC_WAL_VERSION {
+            return Ok(zstd::decode_all(payload).ok());
+        }
+        let Some((&codec, data)) = payload.split_first() else {
+            return Ok(None);
+        };
+        let decompressed = match codec {
+            CODEC_RAW => Ok(data.to_vec()),
+            CODEC_ZSTD => zstd::decode_all(data),
+            CODEC_ZSTD_DICT if data.len() >= 4 => {
+                let id = read_u32(data, 0);
+                let dictionary = match self.dictionaries.entry(id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(load_dictionary(&self.dir, id)?),
+                };
+                decode_with_dictionary(&data[4..], dictionary)
             }
+            _ => return Ok(None),
+        };
+        Ok(decompressed.ok())
+    }
+}
 
-            let payload = &buf[offset..offset + length];
-            let payload = de_compress_data(payload);
-            callback(payload);
+impl Iterator for WALRecords {
+    type Item = io::Result<WALRecord>;
 
-            offset += length;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = match self.read_record() {
+            // The file shrank while reading, same as a torn record
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            record => record,
+        };
+        match record {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
         }
+    }
+}
 
-        Ok(())
+/// Compression of new records, the same for every WAL of a KV
+pub struct WALCompressor {
+    codec: Codec,
+    // Records shorter than this are stored raw
+    threshold: usize,
+}
+
+enum Codec {
+    Raw,
+    Zstd(i32),
+    ZstdDict {
+        id: u32,
+        dictionary: EncoderDictionary<'static>,
+    },
+}
+
+impl WALCompressor {
+    /// A dictionary is saved in `wal_dir`, so records written with it can be replayed
+    /// after the compression option changes.
+    pub fn new(wal_dir: &Path, compression: &WALCompression, threshold: usize) -> io::Result<Self> {
+        let codec = match compression {
+            WALCompression::None => Codec::Raw,
+            WALCompression::Zstd { level } => Codec::Zstd(*level),
+            WALCompression::ZstdDict { level, dictionary } => Codec::ZstdDict {
+                id: save_dictionary(wal_dir, dictionary)?,
+                dictionary: EncoderDictionary::copy(dictionary, *level),
+            },
+        };
+        Ok(Self { codec, threshold })
+    }
+
+    /// Encode a payload as `[codec u8][data]`
+    fn compress(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let codec = if payload.len() < self.threshold {
+            &Codec::Raw
+        } else {
+            &self.codec
+        };
+        let mut buf = Vec::with_capacity(payload.len() + 1);
+        match codec {
+            Codec::Raw => {
+                buf.push(CODEC_RAW);
+                buf.extend_from_slice(payload);
+            }
+            Codec::Zstd(level) => {
+                buf.push(CODEC_ZSTD);
+                zstd::stream::copy_encode(payload, &mut buf, *level)?;
+            }
+            Codec::ZstdDict { id, dictionary } => {
+                buf.push(CODEC_ZSTD_DICT);
+                buf.extend_from_slice(&id.to_le_bytes());
+                let mut encoder =
+                    zstd::stream::Encoder::with_prepared_dictionary(buf, dictionary)?;
+                encoder.write_all(payload)?;
+                buf = encoder.finish()?;
+            }
+        }
+        Ok(buf)
+    }
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+fn dictionary_path(wal_dir: &Path, id: u32) -> PathBuf {
+    wal_dir.join(format!("{:08x}{}", id, DICTIONARY_SUFFIX))
+}
+
+/// Save a dictionary under its checksum unless it is already there, returns its id
+fn save_dictionary(wal_dir: &Path, dictionary: &[u8]) -> io::Result<u32> {
+    let id = crc32fast::hash(dictionary);
+    let path = dictionary_path(wal_dir, id);
+    if fs::read(&path).ok().as_deref() != Some(dictionary) {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(dictionary)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        sync_dir(wal_dir)?;
+    }
+    Ok(id)
+}
+
+fn load_dictionary(wal_dir: &Path, id: u32) -> io::Result<DecoderDictionary<'static>> {
+    let path = dictionary_path(wal_dir, id);
+    let dictionary = fs::read(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read WAL dictionary {}: {}", path.display(), e),
+        )
+    })?;
+    Ok(DecoderDictionary::copy(&dictionary))
+}
+
+fn decode_with_dictionary(data: &[u8], dictionary: &DecoderDictionary) -> io::Result<Vec<u8>> {
+    let mut decoder = zstd::stream::Decoder::with_prepared_dictionary(data, dictionary)?;
+    let mut buf = Vec::new();
+    decoder.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn file_header() -> [u8; WAL_HEADER_LEN as usize] {
+    let mut header = [0u8; WAL_HEADER_LEN as usize];
+    header[..4].copy_from_slice(WAL_MAGIC);
+    header[4] = WAL_VERSION;
+    header
+}
+
+/// Read the version from the file header, None if the file is empty or its header is torn
+fn read_version(file: &File, file_len: u64) -> io::Result<Option<u8>> {
+    let mut header = [0u8; WAL_HEADER_LEN as usize];
+    let len = file_len.min(WAL_HEADER_LEN) as usize;
+    file.read_exact_at(&mut header[..len], 0)?;
+
+    let magic_len = len.min(WAL_MAGIC.len());
+    if header[..magic_len] != WAL_MAGIC[..magic_len] {
+        return Ok(Some(LEGACY_WAL_VERSION));
+    }
+    if len < WAL_HEADER_LEN as usize {
+        return Ok(None);
+    }
+    match header[4] {
+        version @ 1..=WAL_VERSION => Ok(Some(version)),
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported WAL version {}", version),
+        )),
     }
 }
 
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
 const WAL_FILE_SUFFIX: &str = ".wal";
 
 /// Generate WAL file path
@@ -111,10 +607,10 @@ pub fn get_all_wal_ids<P: AsRef<Path>>(path: P) -> Vec<u64> {
             let path = entry.path();
             if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
                 // Assume filename format is <u64>.wal
-                if let Some(id_str) = file_name.strip_suffix(WAL_FILE_SUFFIX) {
-                    if let Ok(id) = id_str.parse::<u64>() {
-                        ids.push(id);
-                    }
+                if let Some(id_str) = file_name.strip_suffix(WAL_FILE_SUFFIX)
+                    && let Ok(id) = id_str.parse::<u64>()
+                {
+                    ids.push(id);
                 }
             }
         }
@@ -122,10 +618,237 @@ pub fn get_all_wal_ids<P: AsRef<Path>>(path: P) -> Vec<u64> {
     ids
 }
 
-fn compress_data(data: &[u8]) -> Vec<u8> {
-    zstd::encode_all(data, 3).unwrap()
-}
+// Level of every record in WAL files older than `CODEC_WAL_VERSION`
+const LEGACY_ZSTD_LEVEL: i32 = 3;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn zstd_compressor() -> WALCompressor {
+        WALCompressor::new(Path::new(""), &WALCompression::default(), 0).unwrap()
+    }
+
+    fn replay_all(wal: &WAL, skip_corrupt: bool) -> (Vec<Vec<u8>>, ReplayResult) {
+        let mut records = Vec::new();
+        let result = wal
+            .replay(skip_corrupt, |record| {
+                records.push(record);
+                true
+            })
+            .unwrap();
+        (records, result)
+    }
+
+    #[test]
+    fn test_wal_replay_detects_torn_and_corrupt_records() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        let records: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 100]).collect();
+        let mut ends = Vec::new();
+        {
+            let mut wal = WAL::open(&path).unwrap();
+            let compressor = zstd_compressor();
+            for record in &records {
+                let record = std::slice::from_ref(record);
+                ends.push(wal.write_records(record, &compressor, Durability::None).unwrap());
+            }
+        }
+
+        let wal = WAL::open(&path).unwrap();
+        let (replayed, result) = replay_all(&wal, false);
+        assert_eq!(replayed, records);
+        assert_eq!(result.valid_len, ends[2]);
+        assert_eq!(result.corrupt_offset, None);
+
+        // Torn tail
+        let mut bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let (replayed, result) = replay_all(&wal, false);
+        assert_eq!(replayed, records[..2]);
+        assert_eq!(result.valid_len, ends[1]);
+        assert_eq!(result.corrupt_offset, None);
+
+        // Corrupt payload in the middle
+        bytes[ends[1] as usize - 1] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let (replayed, result) = replay_all(&wal, false);
+        assert_eq!(replayed, records[..1]);
+        assert_eq!(result.valid_len, ends[0]);
+        assert_eq!(result.corrupt_offset, Some(ends[0]));
+
+        let (replayed, result) = replay_all(&wal, true);
+        assert_eq!(replayed, vec![records[0].clone(), records[2].clone()]);
+        assert_eq!(result.valid_len, ends[2]);
+        assert_eq!(result.corrupt_offset, Some(ends[0]));
+        assert_eq!(result.skipped, 1);
+
+        // A corrupt length is not mistaken for a torn tail, and nothing after it is replayed
+        bytes[ends[0] as usize + 3] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+        for skip_corrupt in [false, true] {
+            let (replayed, result) = replay_all(&wal, skip_corrupt);
+            assert_eq!(replayed, records[..1]);
+            assert_eq!(result.valid_len, ends[0]);
+            assert_eq!(result.corrupt_offset, Some(ends[0]));
+        }
+        bytes[ends[0] as usize + 3] ^= 0x01;
+        fs::write(&path, &bytes).unwrap();
+
+        // A record the callback cannot decode is corrupt as well
+        let result = wal.replay(false, |record| record[0] != 2).unwrap();
+        assert_eq!(result.corrupt_offset, Some(ends[0]));
+    }
+
+    #[test]
+    fn test_wal_failed_append_is_removed() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        let compressor = zstd_compressor();
+        let mut wal = WAL::open(&path).unwrap();
+        let end = wal
+            .write_records(&[b"first".to_vec()], &compressor, Durability::None)
+            .unwrap();
+
+        wal.fail_next_append(5, false);
+        assert!(wal.write_records(&[b"failed".to_vec()], &compressor, Durability::Sync).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), end);
+        assert!(!wal.is_poisoned());
+        wal.write_records(&[b"second".to_vec()], &compressor, Durability::None)
+            .unwrap();
+        let (replayed, result) = replay_all(&wal, false);
+        assert_eq!(replayed, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(result.corrupt_offset, None);
+
+        // The torn bytes stay if they cannot be removed
+        wal.fail_next_append(5, true);
+        assert!(wal.write_records(&[b"failed".to_vec()], &compressor, Durability::Sync).is_err());
+        assert!(wal.is_poisoned());
+    }
 
-fn de_compress_data(data: &[u8]) -> Vec<u8> {
-    zstd::decode_all(data).unwrap()
-}
\ No newline at end of file
+    #[test]
+    fn test_wal_legacy_format() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        let payload = zstd::encode_all(&b"legacy"[..], LEGACY_ZSTD_LEVEL).unwrap();
+        let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&payload);
+        fs::write(&path, &bytes).unwrap();
+
+        let mut wal = WAL::open(&path).unwrap();
+        assert_eq!(wal.version(), LEGACY_WAL_VERSION);
+        wal.write_records(&[b"appended".to_vec()], &zstd_compressor(), Durability::None)
+            .unwrap();
+
+        let (replayed, result) = replay_all(&wal, false);
+        assert_eq!(replayed, vec![b"legacy".to_vec(), b"appended".to_vec()]);
+        assert_eq!(result.valid_len, fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn test_wal_torn_header() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        fs::write(&path, &WAL_MAGIC[..3]).unwrap();
+
+        let mut wal = WAL::open(&path).unwrap();
+        assert_eq!(wal.version(), WAL_VERSION);
+        wal.write_records(&[b"record".to_vec()], &zstd_compressor(), Durability::None)
+            .unwrap();
+        let (replayed, _) = replay_all(&wal, false);
+        assert_eq!(replayed, vec![b"record".to_vec()]);
+        assert_eq!(&fs::read(&path).unwrap()[..8], &file_header());
+
+        fs::write(&path, [b'B', b'K', b'W', b'L', 9, 0, 0, 0]).unwrap();
+        assert!(WAL::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_wal_mixed_compression() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        let small = b"small".to_vec();
+        let large = vec![7u8; 1000];
+        let dictionary = Arc::new(b"dictionary content ".repeat(64));
+
+        let compressors = [
+            WALCompressor::new(dir.path(), &WALCompression::None, 0).unwrap(),
+            WALCompressor::new(dir.path(), &WALCompression::Zstd { level: 1 }, 100).unwrap(),
+            WALCompressor::new(
+                dir.path(),
+                &WALCompression::ZstdDict {
+                    level: 3,
+                    dictionary: dictionary.clone(),
+                },
+                0,
+            )
+            .unwrap(),
+        ];
+        let mut wal = WAL::open(&path).unwrap();
+        // Below the threshold records are stored raw
+        assert_eq!(compressors[1].compress(&small).unwrap()[0], CODEC_RAW);
+        assert_eq!(compressors[1].compress(&large).unwrap()[0], CODEC_ZSTD);
+        let records = [small, large];
+        for compressor in &compressors {
+            wal.write_records(&records, compressor, Durability::None).unwrap();
+        }
+        let (replayed, result) = replay_all(&wal, false);
+        assert_eq!(replayed, records.iter().cycle().take(6).cloned().collect::<Vec<_>>());
+        assert_eq!(result.corrupt_offset, None);
+
+        // Without its dictionary the record cannot be decompressed, which is not corruption
+        fs::remove_file(dictionary_path(dir.path(), crc32fast::hash(&dictionary))).unwrap();
+        assert!(wal.replay(true, |_| true).is_err());
+    }
+
+    #[test]
+    fn test_wal_replays_records_without_codec() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        let payload = zstd::encode_all(&b"record"[..], LEGACY_ZSTD_LEVEL).unwrap();
+        let mut bytes = file_header().to_vec();
+        bytes[4] = TAGGED_BATCH_WAL_VERSION;
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        fs::write(&path, &bytes).unwrap();
+
+        let wal = WAL::open(&path).unwrap();
+        assert_eq!(wal.version(), TAGGED_BATCH_WAL_VERSION);
+        let (replayed, _) = replay_all(&wal, false);
+        assert_eq!(replayed, vec![b"record".to_vec()]);
+    }
+
+    #[test]
+    fn test_wal_records_iterator() {
+        let dir = tempdir().unwrap();
+        let path = wal_file_path(dir.path(), 0);
+        // Records larger than the read buffer
+        let records: Vec<Vec<u8>> = (0..3u8)
+            .map(|i| (0..READ_BUFFER_SIZE + 7).map(|j| (j % 251) as u8 ^ i).collect())
+            .collect();
+        let mut wal = WAL::open(&path).unwrap();
+        let end = wal
+            .write_records(&records, &zstd_compressor(), Durability::None)
+            .unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[0xff; 3]);
+        fs::write(&path, &bytes).unwrap();
+
+        let mut iter = WALRecords::open(&path).unwrap();
+        assert_eq!(iter.version(), WAL_VERSION);
+        assert_eq!(iter.offset(), WAL_HEADER_LEN);
+        let read: Vec<WALRecord> = iter.by_ref().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[0].offset, WAL_HEADER_LEN);
+        assert_eq!(read[1].offset, read[0].end_offset);
+        assert_eq!(read[2].end_offset, end);
+        for (record, expected) in read.into_iter().zip(&records) {
+            assert_eq!(record.payload.as_ref(), Some(expected));
+        }
+        // The torn tail is not part of the valid length
+        assert_eq!(iter.offset(), end);
+        assert!(iter.next().is_none());
+    }
+}
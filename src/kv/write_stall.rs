@@ -0,0 +1,153 @@
+use crate::kv::KVError;
+use crate::kv::flush::FlushState;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Limits on data waiting to be flushed, past which writes are slowed down or stopped.
+///
+/// Bytes are the memory of the current buffer and the flushing buffers, see `KV::buffer_usage`.
+#[derive(Clone)]
+pub struct WriteStallOptions {
+    /// Slow down writes once this many buffers wait to be flushed
+    pub soft_pending_buffers: usize,
+    /// Stop writes once this many buffers wait to be flushed
+    pub hard_pending_buffers: usize,
+    pub soft_pending_bytes: usize,
+    pub hard_pending_bytes: usize,
+    /// Delay of every batch written past a soft limit
+    pub slowdown: Duration,
+    pub stop_behavior: StopBehavior,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        WriteStallOptions {
+            soft_pending_buffers: 8,
+            hard_pending_buffers: 16,
+            soft_pending_bytes: 512 * 1024 * 1024,
+            hard_pending_bytes: 1024 * 1024 * 1024,
+            slowdown: Duration::from_millis(1),
+            stop_behavior: StopBehavior::default(),
+        }
+    }
+}
+
+/// What a write does past a hard limit
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum StopBehavior {
+    /// Wait until enough buffers are flushed
+    #[default]
+    Block,
+    /// Fail with `KVError::WriteStall`
+    Fail,
+}
+
+/// Whether writes are currently held back
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WriteStallCondition {
+    #[default]
+    Normal,
+    /// Past a soft limit
+    Delayed,
+    /// Past a hard limit
+    Stopped,
+}
+
+/// Counters of held back writes since the KV was opened
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct WriteStallStats {
+    pub condition: WriteStallCondition,
+    /// Batches delayed past a soft limit
+    pub delayed_writes: u64,
+    /// Batches blocked past a hard limit
+    pub stopped_writes: u64,
+    /// Batches failed with `KVError::WriteStall`
+    pub rejected_writes: u64,
+    /// Total time writers spent delayed or blocked
+    pub stall_time: Duration,
+}
+
+/// Buffers and bytes waiting to be flushed
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Pending {
+    pub buffers: usize,
+    pub bytes: usize,
+}
+
+pub(crate) struct WriteStall {
+    opts: WriteStallOptions,
+    delayed_writes: AtomicU64,
+    stopped_writes: AtomicU64,
+    rejected_writes: AtomicU64,
+    stall_micros: AtomicU64,
+}
+
+impl WriteStall {
+    pub fn new(opts: WriteStallOptions) -> Self {
+        Self {
+            opts,
+            delayed_writes: AtomicU64::new(0),
+            stopped_writes: AtomicU64::new(0),
+            rejected_writes: AtomicU64::new(0),
+            stall_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn condition(&self, pending: Pending) -> WriteStallCondition {
+        // Only a flush brings the bytes down, with nothing to flush writes go on
+        // until the current buffer is rotated
+        if pending.buffers > 0
+            && (pending.buffers >= self.opts.hard_pending_buffers
+                || pending.bytes >= self.opts.hard_pending_bytes)
+        {
+            WriteStallCondition::Stopped
+        } else if pending.buffers >= self.opts.soft_pending_buffers
+            || pending.bytes >= self.opts.soft_pending_bytes
+        {
+            WriteStallCondition::Delayed
+        } else {
+            WriteStallCondition::Normal
+        }
+    }
+
+    /// Hold back a write according to what is pending, must be called without holding
+    /// any lock the flush thread needs
+    pub fn throttle(
+        &self,
+        flush_state: &FlushState,
+        pending: impl Fn() -> Pending,
+    ) -> Result<(), KVError> {
+        let start = Instant::now();
+        match self.condition(pending()) {
+            WriteStallCondition::Normal => return Ok(()),
+            WriteStallCondition::Delayed => {
+                self.delayed_writes.fetch_add(1, Ordering::Relaxed);
+                sleep(self.opts.slowdown);
+            }
+            WriteStallCondition::Stopped if self.opts.stop_behavior == StopBehavior::Fail => {
+                self.rejected_writes.fetch_add(1, Ordering::Relaxed);
+                return Err(KVError::WriteStall);
+            }
+            WriteStallCondition::Stopped => {
+                self.stopped_writes.fetch_add(1, Ordering::Relaxed);
+                flush_state.wait_flushed_while(|| {
+                    self.condition(pending()) == WriteStallCondition::Stopped
+                });
+            }
+        }
+        self.stall_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn stats(&self, pending: Pending) -> WriteStallStats {
+        WriteStallStats {
+            condition: self.condition(pending),
+            delayed_writes: self.delayed_writes.load(Ordering::Relaxed),
+            stopped_writes: self.stopped_writes.load(Ordering::Relaxed),
+            rejected_writes: self.rejected_writes.load(Ordering::Relaxed),
+            stall_time: Duration::from_micros(self.stall_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
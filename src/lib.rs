@@ -1,3 +1,5 @@
+#![allow(clippy::upper_case_acronyms)]
+
 pub mod kv;
 //pub use kv::KV; 
 //pub use kv::KVOptions; 